@@ -1,15 +1,23 @@
-use std::fs::File;
-use std::sync::Mutex;
-use std::error::Error;
-use std::path::PathBuf;
+use std::fmt;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
-use std::process::Command;
-use std::collections::HashSet;
-use lazy_static::lazy_static;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use serde::Deserialize;
+use similar::TextDiff;
 use finch_frontend_api::{
   FinchClass,
+  FinchEnum,
   FinchNew,
   FinchDrop,
+  FinchClone,
+  FinchOperator,
+  FinchOperatorKind,
   FinchMethod,
   FinchStatic,
   FinchGetter,
@@ -19,31 +27,450 @@ use finch_frontend_api::{
   get_package_name,
 };
 
-static mut CRATE_NAME: String = String::new();
+/// Where in a signature an unsupported type was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Position {
+  Argument(usize),
+  Return,
+  /// A field of an enum variant.
+  Field(usize),
+}
+
+impl fmt::Display for Position {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Position::Argument(i) => write!(f, "argument {}", i),
+      Position::Return => write!(f, "return type"),
+      Position::Field(i) => write!(f, "field {}", i),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum Error {
+  /// `type_name` has no C++ equivalent. `item` is the method, field or enum
+  /// variant of `class` whose signature uses it.
+  UnsupportedType {
+    class: String,
+    item: String,
+    position: Position,
+    type_name: String,
+  },
+  /// The formatter failed on the file at `path`.
+  Format {
+    path: PathBuf,
+    message: String,
+  },
+  /// `Builder::check` found generated files that are missing or out of date.
+  Stale {
+    files: Vec<PathBuf>,
+    /// Unified diff from the files on disk to the expected output.
+    diff: String,
+  },
+  /// The configuration file at `path` couldn't be parsed.
+  Config {
+    path: PathBuf,
+    message: String,
+  },
+  /// The setting `name` has a value that can't be used.
+  InvalidOption {
+    name: String,
+    message: String,
+  },
+  /// The frontend failed. Only its message is kept, as its error type isn't
+  /// `Send` and would keep generation from running on other threads.
+  Frontend(String),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::UnsupportedType { class, item, position, type_name } => {
+        write!(f, "unsupported type `{}` in {} of `{}::{}`", type_name, position, class, item)
+      },
+      Error::Format { path, message } => write!(f, "failed to format {}: {}", path.display(), message),
+      Error::Stale { files, diff } => {
+        writeln!(f, "generated files are out of date:")?;
+        for file in files {
+          writeln!(f, "  {}", file.display())?;
+        }
+        write!(f, "\n{}", diff)
+      },
+      Error::Config { path, message } => write!(f, "invalid configuration in {}: {}", path.display(), message),
+      Error::InvalidOption { name, message } => write!(f, "invalid {}: {}", name, message),
+      Error::Frontend(err) => write!(f, "{}", err),
+      Error::Io(err) => write!(f, "{}", err),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Box<dyn std::error::Error>> for Error {
+  fn from(err: Box<dyn std::error::Error>) -> Self {
+    Error::Frontend(err.to_string())
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(err: std::io::Error) -> Self {
+    Error::Io(err)
+  }
+}
+
+/// A type conversion failure, located by whichever item was being converted.
+struct Unsupported(String);
+
+impl Unsupported {
+  fn at(self, class: &str, item: &str, position: Position) -> Error {
+    Error::UnsupportedType {
+      class: class.to_string(),
+      item: item.to_string(),
+      position,
+      type_name: self.0,
+    }
+  }
+}
+
+const SLICE_DEFINITION: &str = "
+  #ifndef finch_bindgen_SLICE
+  #define finch_bindgen_SLICE
+  namespace finch {
+    template <typename T>
+    class slice {
+    public:
+      slice() : ptr(nullptr), len(0) {}
+      slice(const T *ptr, size_t len) : ptr(ptr), len(len) {}
+      slice(const std::vector<T> &vec) : ptr(vec.data()), len(vec.size()) {}
+
+      const T *data() const { return ptr; }
+      size_t size() const { return len; }
+      bool empty() const { return len == 0; }
+      const T *begin() const { return ptr; }
+      const T *end() const { return ptr + len; }
+      const T &operator[](size_t i) const { return ptr[i]; }
+      std::vector<T> to_vector() const { return std::vector<T>(begin(), end()); }
+
+    private:
+      const T *ptr;
+      size_t len;
+    };
+  }
+  #endif
+";
+
+const ITERATOR_DEFINITION: &str = "
+  #ifndef finch_bindgen_ITERATOR
+  #define finch_bindgen_ITERATOR
+  namespace finch {
+    template <typename I, typename T>
+    class input_iterator {
+    public:
+      typedef std::input_iterator_tag iterator_category;
+      typedef T value_type;
+      typedef std::ptrdiff_t difference_type;
+      typedef T *pointer;
+      typedef T &reference;
+
+      input_iterator() : source(nullptr) {}
+
+      explicit input_iterator(I *source) : source(source) {
+        ++*this;
+      }
+
+      explicit input_iterator(std::shared_ptr<I> owner) : owner(owner), source(owner.get()) {
+        ++*this;
+      }
+
+      reference operator*() const { return *this->current; }
+      pointer operator->() const { return &*this->current; }
+
+      input_iterator &operator++() {
+        this->current = this->source->next();
+        if (!this->current.has_value()) {
+          this->source = nullptr;
+          this->owner.reset();
+        }
+        return *this;
+      }
+
+      void operator++(int) { ++*this; }
+
+      bool operator==(const input_iterator &other) const { return this->source == other.source; }
+      bool operator!=(const input_iterator &other) const { return this->source != other.source; }
+
+    #ifdef __cpp_lib_ranges
+      bool operator==(std::default_sentinel_t) const { return this->source == nullptr; }
+    #endif
+
+    private:
+      std::shared_ptr<I> owner;
+      I *source;
+      mutable nonstd::optional<T> current;
+    };
+  }
+  #endif
+";
+
+const EXPECTED_DEFINITION: &str = "
+  #if defined(__has_include)
+    #if __has_include(<expected>) && __cplusplus > 202002L
+      #include <expected>
+    #endif
+  #endif
+
+  #ifndef finch_bindgen_EXPECTED
+  #define finch_bindgen_EXPECTED
+  namespace finch {
+  #ifdef __cpp_lib_expected
+    template <typename T, typename E>
+    using expected = std::expected<T, E>;
+    template <typename E>
+    using unexpected = std::unexpected<E>;
+  #else
+    template <typename T, typename E>
+    using expected = tl::expected<T, E>;
+    template <typename E>
+    using unexpected = tl::unexpected<E>;
+  #endif
+  }
+  #endif
+";
+
+const STD_EXPECTED_DEFINITION: &str = "
+  #ifndef finch_bindgen_EXPECTED
+  #define finch_bindgen_EXPECTED
+  namespace finch {
+    template <typename T, typename E>
+    using expected = std::expected<T, E>;
+    template <typename E>
+    using unexpected = std::unexpected<E>;
+  }
+  #endif
+";
+
+const EXCEPTION_DEFINITION: &str = r#"
+  class Exception : public std::runtime_error {
+  public:
+    explicit Exception(const std::string &message) : std::runtime_error(message) {}
+
+    [[noreturn]] static void raise(const std::string &message) {
+      #ifdef finch_bindgen_EXCEPTIONS
+        throw Exception(message);
+      #else
+        std::cout << "fatal: Result returned Err(\"" << message << "\")" << std::endl;
+        abort();
+      #endif
+    }
+  };
+
+  template <typename T>
+  class TypedException : public Exception {
+  public:
+    explicit TypedException(T value) : Exception("Result returned Err"), value(std::move(value)) {}
+
+    const T &get_value() const {
+      return this->value;
+    }
+
+    [[noreturn]] static void raise(T value) {
+      #ifdef finch_bindgen_EXCEPTIONS
+        throw TypedException<T>(std::move(value));
+      #else
+        std::cout << "fatal: Result returned Err" << std::endl;
+        abort();
+      #endif
+    }
+
+  private:
+    T value;
+  };
+"#;
+
+/// State for a single generation run, threaded through the `ToCPP*` traits so
+/// that independent runs never share anything.
+#[derive(Clone)]
+struct Context {
+  crate_name: String,
+  /// The namespace the public classes are declared in, e.g. `acme::geometry`.
+  namespace: String,
+  expected_results: bool,
+  unique_ptr: bool,
+  cpp_standard: CppStandard,
+  /// Marks out-of-class definitions `inline`, which they have to be whenever
+  /// they end up in a header that more than one file includes.
+  inline_definitions: bool,
+  /// Enums keyed by their bindgen name.
+  enums: BTreeMap<String, FinchEnum>,
+  /// Rust module path of the classes and enums declared outside the crate
+  /// root, keyed by name. Empty unless module paths are mirrored.
+  modules: BTreeMap<String, String>,
+  /// Item type of every class that can be iterated, keyed by the name generated
+  /// code refers to the class by.
+  iterators: HashMap<String, FinchType>,
+  /// Explicit template instantiations to insert into the bindgen header, sorted
+  /// so the header doesn't change between runs.
+  templates: BTreeSet<String>,
+  /// Bindgen names of the enums used as `Result` errors.
+  errors: HashSet<String>,
+  use_optional: bool,
+  use_vector: bool,
+  use_slice: bool,
+  use_expected: bool,
+  use_iterator: bool,
+  use_variant: bool,
+}
+
+impl Context {
+  fn new(crate_name: String, config: &Config) -> Result<Self, Error> {
+    let namespace = match &config.namespace {
+      Some(namespace) => namespace.trim_start_matches("::").to_string(),
+      None => crate_name.clone(),
+    };
 
-static mut USE_OPTIONAL: bool = false;
+    // An empty name would declare an anonymous namespace, whose classes can't
+    // be named from other files.
+    if !namespace.split("::").all(is_identifier) {
+      return Err(Error::InvalidOption {
+        name: "namespace".to_string(),
+        message: format!("`{}` is not a namespace name", namespace),
+      });
+    }
+
+    Ok(Self {
+      namespace,
+      crate_name,
+      expected_results: config.expected_results,
+      unique_ptr: config.unique_ptr,
+      cpp_standard: config.cpp_standard,
+      inline_definitions: !config.separate_source,
+      enums: BTreeMap::new(),
+      modules: BTreeMap::new(),
+      iterators: HashMap::new(),
+      templates: BTreeSet::new(),
+      errors: HashSet::new(),
+      use_optional: false,
+      use_vector: false,
+      use_slice: false,
+      use_expected: false,
+      use_iterator: false,
+      use_variant: false,
+    })
+  }
+
+  /// Declares each class and enum of `modules` in a namespace named after its
+  /// Rust module, nested in the public namespace.
+  fn mirror_modules(&mut self, modules: BTreeMap<String, String>) -> Result<(), Error> {
+    if let Some((name, module)) = modules.iter().find(|(_, module)| !module.split("::").all(is_identifier)) {
+      return Err(Error::InvalidOption {
+        name: "module-namespaces".to_string(),
+        message: format!("the module `{}` of `{}` is not a namespace name", module, name),
+      });
+    }
+
+    self.modules = modules;
+    Ok(())
+  }
+
+  /// The lines opening and closing the namespace `path`, nested one level per
+  /// `::` before C++17.
+  fn namespace_block(&self, path: &str) -> (String, String) {
+    if self.cpp_standard >= CppStandard::Cpp17 {
+      return (format!("namespace {} {{", path), "}".to_string());
+    }
+
+    let parts = path.split("::").collect::<Vec<&str>>();
+    (
+      parts.iter().map(|x| format!("namespace {} {{", x)).collect::<Vec<String>>().join(" "),
+      "}".repeat(parts.len()),
+    )
+  }
+
+  /// The class or enum `name` with the namespace it is declared in.
+  fn full_name(&self, name: &str) -> String {
+    match self.modules.get(name) {
+      Some(module) => format!("{}::{}::{}", self.namespace, module, name),
+      None => format!("{}::{}", self.namespace, name),
+    }
+  }
+
+  /// How generated code refers to the class or enum `name`. Those declared in
+  /// a module namespace are qualified, as the code may be in another module.
+  fn qualified(&self, name: &str) -> String {
+    if self.modules.contains_key(name) {
+      format!("::{}", self.full_name(name))
+    } else {
+      name.to_string()
+    }
+  }
+
+  /// Wraps the declarations or definitions of the class or enum `name` in the
+  /// namespace of its module.
+  fn in_module(&self, name: &str, code: String) -> String {
+    match self.modules.get(name) {
+      Some(module) => {
+        let (open, close) = self.namespace_block(module);
+        format!("{}\n{}\n{}\n", open, code, close)
+      },
+      None => code,
+    }
+  }
+
+  /// The specifier out-of-class definitions start with.
+  fn inline(&self) -> &'static str {
+    if self.inline_definitions { "inline " } else { "" }
+  }
+
+  fn optional(&self) -> &'static str {
+    if self.cpp_standard >= CppStandard::Cpp17 { "std::optional" } else { "nonstd::optional" }
+  }
+
+  fn nullopt(&self) -> &'static str {
+    if self.cpp_standard >= CppStandard::Cpp17 { "std::nullopt" } else { "nonstd::nullopt" }
+  }
+
+  /// The type Rust strings are passed in as.
+  fn string_arg(&self) -> &'static str {
+    if self.cpp_standard >= CppStandard::Cpp17 { "std::string_view" } else { "std::string" }
+  }
 
-lazy_static! {
-  static ref TEMPLATES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+  /// The view primitive slices are passed in and returned as.
+  fn slice(&self, element: &str) -> String {
+    if self.cpp_standard >= CppStandard::Cpp20 {
+      format!("std::span<const {}>", element)
+    } else {
+      format!("finch::slice<{}>", element)
+    }
+  }
 }
 
 trait ToCPPType {
-  fn to_cpp_type(&self) -> String;
-  fn convert_arg(&self, body: String) -> String;
-  fn convert_ret(&self, body: String) -> String;
+  fn to_cpp_type(&self, ctx: &mut Context) -> Result<String, Unsupported>;
+  fn to_cpp_arg_type(&self, ctx: &mut Context) -> Result<String, Unsupported>;
+  fn convert_arg(&self, ctx: &mut Context, body: String) -> Result<String, Unsupported>;
+  fn convert_ret(&self, ctx: &mut Context, body: String) -> Result<String, Unsupported>;
 }
 
 impl ToCPPType for FinchType {
-  fn to_cpp_type(&self) -> String {
-    if self.display_name.starts_with(&format!("finch::bindgen::{}::___finch_bindgen___{0}___class___", unsafe { &CRATE_NAME })) {
-      return self.display_name.split("___").collect::<Vec<&str>>()[4].to_string() + "*";
+  fn to_cpp_type(&self, ctx: &mut Context) -> Result<String, Unsupported> {
+    if let Some(class) = class_name(ctx, self) {
+      if ctx.unique_ptr {
+        return Ok(format!("std::unique_ptr<{}>", class));
+      } else {
+        return Ok(class + "*");
+      }
+    }
+
+    if let Some(enum_) = find_enum(ctx, &self.display_name) {
+      return Ok(ctx.qualified(&enum_.name));
     }
 
     if let Some(canonical_type) = self.canonical_type.as_ref() {
-      return canonical_type.to_cpp_type();
+      return canonical_type.to_cpp_type(ctx);
     }
   
-    match self.kind {
+    Ok(match self.kind {
       TypeKind::Void |
       TypeKind::Bool |
       TypeKind::CharS | TypeKind::CharU | TypeKind::SChar | TypeKind::UChar |
@@ -52,32 +479,119 @@ impl ToCPPType for FinchType {
       TypeKind::Float | TypeKind::Double => self.display_name.clone(),
   
       TypeKind::Record => {
-        if self.display_name == format!("finch::bindgen::{}::FinchString", unsafe { &CRATE_NAME }) {
+        if self.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
           "std::string".to_string()
-        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", unsafe { &CRATE_NAME }).as_str()) {
-          unsafe { USE_OPTIONAL = true };
-          format!("nonstd::optional<{}>", self.template_argument_types.as_ref().unwrap()[0].as_ref().unwrap().to_cpp_type())
-        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchResult", unsafe { &CRATE_NAME }).as_str()) {
-          self.template_argument_types.as_ref().unwrap()[0].as_ref().unwrap().to_cpp_type()
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", ctx.crate_name).as_str()) {
+          ctx.use_optional = true;
+          let inner_type = template_argument(self, 0)?.to_cpp_type(ctx)?;
+          format!("{}<{}>", ctx.optional(), inner_type)
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchResult", ctx.crate_name).as_str()) {
+          let inner_type = template_argument(self, 0)?.to_cpp_type(ctx)?;
+          if ctx.expected_results {
+            ctx.use_expected = true;
+            let err_type = match template_argument(self, 1) {
+              Ok(x) => x.to_cpp_type(ctx)?,
+              Err(_) => "std::string".to_string(),
+            };
+            format!("finch::expected<{}, {}>", inner_type, err_type)
+          } else {
+            inner_type
+          }
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchVec", ctx.crate_name).as_str()) {
+          ctx.use_vector = true;
+          format!("std::vector<{}>", template_argument(self, 0)?.to_cpp_type(ctx)?)
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchSlice", ctx.crate_name).as_str()) {
+          ctx.use_vector = true;
+          let inner_type = template_argument(self, 0)?;
+          if is_primitive(inner_type) {
+            ctx.use_slice = true;
+            let inner_type = inner_type.to_cpp_type(ctx)?;
+            ctx.slice(&inner_type)
+          } else {
+            format!("std::vector<{}>", inner_type.to_cpp_type(ctx)?)
+          }
         } else {
-          panic!("unknown type {}", self.display_name)
+          return Err(Unsupported(self.display_name.clone()))
         }
       },
   
-      _ => panic!("unknown type {}", self.display_name)
+      _ => return Err(Unsupported(self.display_name.clone())),
+    })
+  }
+
+  // Borrowed classes are taken by reference, a class passed by value hands
+  // ownership of the object over to Rust and is taken as an rvalue.
+  fn to_cpp_arg_type(&self, ctx: &mut Context) -> Result<String, Unsupported> {
+    if let Some(class) = class_name(ctx, self) {
+      return Ok(match class_arg(self) {
+        ClassArg::Shared => format!("const {} &", class),
+        ClassArg::Mutable => format!("{} &", class),
+        ClassArg::Owned => format!("{} &&", class),
+      });
+    }
+
+    if self.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
+      return Ok(ctx.string_arg().to_string());
     }
+
+    self.to_cpp_type(ctx)
   }
 
-  fn convert_arg(&self, body: String) -> String {
-    if self.display_name.starts_with(&format!("finch::bindgen::{}::___finch_bindgen___{0}___class___", unsafe { &CRATE_NAME })) {
-      return format!("{}.self", body);
+  fn convert_arg(&self, ctx: &mut Context, body: String) -> Result<String, Unsupported> {
+    if let Some(class) = class_name(ctx, self) {
+      if class_arg(self) != ClassArg::Owned {
+        return Ok(format!("{}.self", body));
+      }
+
+      return Ok(take_object(&class, &body));
+    }
+
+    if let Some(enum_) = find_enum(ctx, &self.display_name) {
+      if is_fieldless(&enum_) {
+        return Ok(format!("static_cast<{}>({})", enum_.c_name, body));
+      }
+
+      let name = ctx.qualified(&enum_.name);
+      let cases = enum_.variants.iter().map(|variant| {
+        let fields = variant.field_names.iter().enumerate().map(|(i, x)| {
+          Ok(format!("
+            finch.{}.{} = {};",
+            to_snake_case(&variant.name),
+            x,
+            convert_element_arg(ctx, &variant.field_types[i], format!("value.as_{}().{}", to_snake_case(&variant.name), x))?,
+          ))
+        }).collect::<Result<Vec<String>, _>>()?.join("");
+
+        Ok(format!("
+          case {name}::Tag::{variant}:
+            finch.tag = {c_name}::Tag::{variant};{fields}
+            break;",
+          name=name,
+          c_name=enum_.c_name,
+          variant=variant.name,
+          fields=fields,
+        ))
+      }).collect::<Result<Vec<String>, _>>()?.join("");
+
+      return Ok(format!("
+        [](const {name} &value) -> {c_name} {{
+          {c_name} finch;
+          switch (value.get_tag()) {{{cases}
+          }}
+          return finch;
+        }}({body})",
+        name=name,
+        c_name=enum_.c_name,
+        cases=cases,
+        body=body,
+      ));
     }
 
     if let Some(canonical_type) = self.canonical_type.as_ref() {
-      return canonical_type.convert_arg(body);
+      return canonical_type.convert_arg(ctx, body);
     }
   
-    match self.kind {
+    Ok(match self.kind {
       TypeKind::Void |
       TypeKind::Bool |
       TypeKind::CharS | TypeKind::CharU | TypeKind::SChar | TypeKind::UChar |
@@ -86,24 +600,25 @@ impl ToCPPType for FinchType {
       TypeKind::Float | TypeKind::Double => body,
   
       TypeKind::Record => {
-        if self.display_name == format!("finch::bindgen::{}::FinchString", unsafe { &CRATE_NAME }) {
+        if self.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
           format!("
-            [](std::string str) -> finch::bindgen::{}::FinchString {{
-              return finch::bindgen::{0}::___finch_bindgen___{0}___builtin___FinchString___new(reinterpret_cast<const uint8_t *>(str.data()), str.size());
-            }}({})",
-            unsafe { &CRATE_NAME },
+            []({} str) -> finch::bindgen::{1}::FinchString {{
+              return finch::bindgen::{1}::___finch_bindgen___{1}___builtin___FinchString___new(reinterpret_cast<const uint8_t *>(str.data()), str.size());
+            }}({2})",
+            ctx.string_arg(),
+            ctx.crate_name,
             body
           )
-        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", unsafe { &CRATE_NAME }).as_str()) {
-          unsafe { USE_OPTIONAL = true };
-          let original_inner_type = self.template_argument_types.as_ref().unwrap()[0].as_ref().unwrap();
-          let inner_type = original_inner_type.to_cpp_type();
-          let inner_body = original_inner_type.convert_arg("opt.value()".to_string());
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", ctx.crate_name).as_str()) {
+          ctx.use_optional = true;
+          let original_inner_type = template_argument(self, 0)?;
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+          let inner_body = convert_element_arg(ctx, original_inner_type, "opt.value()".to_string())?;
   
-          TEMPLATES.lock().unwrap().insert(format!("template struct FinchOption<{}>;", original_inner_type.display_name));
+          ctx.templates.insert(format!("template struct FinchOption<{}>;", original_inner_type.display_name));
   
           format!("
-            [](nonstd::optional<{inner_type}> opt) -> finch::bindgen::{crate_name}::FinchOption<{original_inner_type}> {{
+            [](const {optional}<{inner_type}> &opt) -> finch::bindgen::{crate_name}::FinchOption<{original_inner_type}> {{
               finch::bindgen::{crate_name}::FinchOption<{original_inner_type}> finch;
               if (opt.has_value()) {{
                 finch.tag = finch::bindgen::{crate_name}::FinchOption<{original_inner_type}>::Tag::Some;
@@ -113,32 +628,174 @@ impl ToCPPType for FinchType {
               }}
               return finch;
             }}({body})",
-            crate_name=unsafe { &CRATE_NAME },
+            optional=ctx.optional(),
+            crate_name=ctx.crate_name,
+            original_inner_type=original_inner_type.display_name,
+            inner_type=inner_type,
+            inner_body=inner_body,
+            body=body,
+          )
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchVec", ctx.crate_name).as_str()) {
+          ctx.use_vector = true;
+          let original_inner_type = template_argument(self, 0)?;
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+          let inner_body = convert_element_arg(ctx, original_inner_type, "value".to_string())?;
+
+          ctx.templates.insert(format!("template struct FinchVec<{}>;", original_inner_type.display_name));
+
+          // The buffer is allocated by Rust, so the Vec it is turned into owns it
+          // like any other, and the converted elements are moved into it.
+          format!("
+            [](const std::vector<{inner_type}> &vec) -> finch::bindgen::{crate_name}::FinchVec<{original_inner_type}> {{
+              finch::bindgen::{crate_name}::FinchVec<{original_inner_type}> finch;
+              finch.ptr = static_cast<{original_inner_type} *>(finch::bindgen::{crate_name}::___finch_bindgen___{crate_name}___builtin___FinchVec___new(vec.size(), sizeof({original_inner_type}), alignof({original_inner_type})));
+              finch.len = vec.size();
+              finch.cap = vec.size();
+              for (size_t i = 0; i < vec.size(); i++) {{
+                auto &value = vec[i];
+                new (&finch.ptr[i]) {original_inner_type}{{ {inner_body} }};
+              }}
+              return finch;
+            }}({body})",
+            crate_name=ctx.crate_name,
             original_inner_type=original_inner_type.display_name,
             inner_type=inner_type,
             inner_body=inner_body,
             body=body,
           )
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchSlice", ctx.crate_name).as_str()) {
+          ctx.use_vector = true;
+          let original_inner_type = template_argument(self, 0)?;
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+
+          ctx.templates.insert(format!("template struct FinchSlice<{}>;", original_inner_type.display_name));
+
+          if is_primitive(original_inner_type) {
+            ctx.use_slice = true;
+            format!("
+              []({slice} slice) -> finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> {{
+                finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> finch;
+                finch.ptr = slice.data();
+                finch.len = slice.size();
+                return finch;
+              }}({body})",
+              slice=ctx.slice(&inner_type),
+              crate_name=ctx.crate_name,
+              original_inner_type=original_inner_type.display_name,
+              body=body,
+            )
+          } else {
+            let inner_body = convert_element_arg(ctx, original_inner_type, "value".to_string())?;
+            let drop = borrowed_element_drop(ctx, original_inner_type, "finch")?;
+            let elements = format!("
+              [](const std::vector<{inner_type}> &vec) -> std::vector<{original_inner_type}> {{
+                std::vector<{original_inner_type}> finch;
+                finch.reserve(vec.size());
+                for (auto &value : vec) {{
+                  finch.push_back({{ {inner_body} }});
+                }}
+                return finch;
+              }}({body})",
+              original_inner_type=original_inner_type.display_name,
+              inner_type=inner_type,
+              inner_body=inner_body,
+              body=body,
+            );
+
+            if drop.is_empty() {
+              format!("
+                [](const std::vector<{original_inner_type}> &vec) -> finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> {{
+                  finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> finch;
+                  finch.ptr = vec.data();
+                  finch.len = vec.size();
+                  return finch;
+                }}({elements})",
+                crate_name=ctx.crate_name,
+                original_inner_type=original_inner_type.display_name,
+                elements=elements,
+              )
+            } else {
+              // Rust only borrows the converted elements, they are freed by the
+              // deleter when the temporary is destroyed after the call.
+              format!("
+                [](const std::shared_ptr<std::vector<{original_inner_type}>> &vec) -> finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> {{
+                  finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> finch;
+                  finch.ptr = vec->data();
+                  finch.len = vec->size();
+                  return finch;
+                }}(std::shared_ptr<std::vector<{original_inner_type}>>(
+                  new std::vector<{original_inner_type}>({elements}),
+                  [](std::vector<{original_inner_type}> *vec) {{
+                    for (auto &finch : *vec) {{
+                      {drop}
+                    }}
+                    delete vec;
+                  }}
+                ))",
+                crate_name=ctx.crate_name,
+                original_inner_type=original_inner_type.display_name,
+                elements=elements,
+                drop=drop,
+              )
+            }
+          }
         } else {
-          panic!("unknown type {}", self.display_name)
+          return Err(Unsupported(self.display_name.clone()))
         }
       },
   
-      _ => panic!("unknown type {}", self.display_name)
-    }
+      _ => return Err(Unsupported(self.display_name.clone())),
+    })
   }
 
-  fn convert_ret(&self, body: String) -> String {
-    if self.display_name.starts_with(&format!("finch::bindgen::{}::___finch_bindgen___{0}___class___", unsafe { &CRATE_NAME })) {
-      let class = self.display_name.split("___").collect::<Vec<&str>>()[4];
-      return format!("new {}({})", class, body);
+  fn convert_ret(&self, ctx: &mut Context, body: String) -> Result<String, Unsupported> {
+    if let Some(class) = class_name(ctx, self) {
+      if ctx.unique_ptr {
+        return Ok(format!("std::unique_ptr<{}>(new {0}({}))", class, body));
+      } else {
+        return Ok(format!("new {}({})", class, body));
+      }
+    }
+
+    if let Some(enum_) = find_enum(ctx, &self.display_name) {
+      if is_fieldless(&enum_) {
+        return Ok(format!("static_cast<{}>({})", ctx.qualified(&enum_.name), body));
+      }
+
+      let name = ctx.qualified(&enum_.name);
+      let cases = enum_.variants.iter().map(|variant| {
+        let fields = variant.field_names.iter().enumerate().map(|(i, x)| {
+          variant.field_types[i].convert_ret(ctx, format!("finch.{}.{}", to_snake_case(&variant.name), x))
+        }).collect::<Result<Vec<String>, _>>()?.join(", ");
+
+        Ok(format!("
+          case {c_name}::Tag::{variant}:
+            return {name}({name}::{variant}{{ {fields} }});",
+          name=name,
+          c_name=enum_.c_name,
+          variant=variant.name,
+          fields=fields,
+        ))
+      }).collect::<Result<Vec<String>, _>>()?.join("");
+
+      return Ok(format!("
+        []({c_name} finch) -> {name} {{
+          switch (finch.tag) {{{cases}
+          }}
+          abort();
+        }}({body})",
+        name=name,
+        c_name=enum_.c_name,
+        cases=cases,
+        body=body,
+      ));
     }
 
     if let Some(canonical_type) = self.canonical_type.as_ref() {
-      return canonical_type.convert_ret(body);
+      return canonical_type.convert_ret(ctx, body);
     }
   
-    match self.kind {
+    Ok(match self.kind {
       TypeKind::Void |
       TypeKind::Bool |
       TypeKind::CharS | TypeKind::CharU | TypeKind::SChar | TypeKind::UChar |
@@ -147,7 +804,7 @@ impl ToCPPType for FinchType {
       TypeKind::Float | TypeKind::Double => body,
   
       TypeKind::Record => {
-        if self.display_name == format!("finch::bindgen::{}::FinchString", unsafe { &CRATE_NAME }) {
+        if self.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
           format!("
             [](finch::bindgen::{}::FinchString finch) -> std::string {{
               std::string str(finch.ptr, finch.len);
@@ -155,173 +812,648 @@ impl ToCPPType for FinchType {
                   ___finch_bindgen___{0}___builtin___FinchString___drop(finch);
               return str;
             }}({})",
-            unsafe { &CRATE_NAME },
+            ctx.crate_name,
             body
           )
-        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", unsafe { &CRATE_NAME }).as_str()) {
-          unsafe { USE_OPTIONAL = true };
-          let original_inner_type = self.template_argument_types.as_ref().unwrap()[0].as_ref().unwrap();
-          let inner_type = original_inner_type.to_cpp_type();
-          let inner_body = original_inner_type.convert_ret("finch.some._0".to_string());
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", ctx.crate_name).as_str()) {
+          ctx.use_optional = true;
+          let original_inner_type = template_argument(self, 0)?;
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+          let inner_body = original_inner_type.convert_ret(ctx, "finch.some._0".to_string())?;
   
-          TEMPLATES.lock().unwrap().insert(format!("template struct FinchOption<{}>;", original_inner_type.display_name));
+          ctx.templates.insert(format!("template struct FinchOption<{}>;", original_inner_type.display_name));
   
           format!("
-            [](finch::bindgen::{crate_name}::FinchOption<{original_inner_type}> finch) -> nonstd::optional<{inner_type}> {{
+            [](finch::bindgen::{crate_name}::FinchOption<{original_inner_type}> finch) -> {optional}<{inner_type}> {{
               if (finch.tag == finch::bindgen::{crate_name}::FinchOption<{original_inner_type}>::Tag::Some) {{
-                return nonstd::optional<{inner_type}>({inner_body});
+                return {optional}<{inner_type}>({inner_body});
               }} else {{
-                return nonstd::nullopt;
+                return {nullopt};
               }}
             }}({body})",
-            crate_name=unsafe { &CRATE_NAME },
+            optional=ctx.optional(),
+            nullopt=ctx.nullopt(),
+            crate_name=ctx.crate_name,
             original_inner_type=original_inner_type.display_name,
             inner_type=inner_type,
             inner_body=inner_body,
             body=body,
           )
-        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchResult", unsafe { &CRATE_NAME }).as_str()) {
-          let original_inner_type = self.template_argument_types.as_ref().unwrap()[0].as_ref().unwrap();
-          let inner_type = original_inner_type.to_cpp_type();
-          let inner_body = original_inner_type.convert_ret("finch.ok._0".to_string());
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchResult", ctx.crate_name).as_str()) {
+          let original_inner_type = template_argument(self, 0)?;
+          let original_err_type = template_argument(self, 1).ok();
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+          let inner_body = original_inner_type.convert_ret(ctx, "finch.ok._0".to_string())?;
+
+          // Results without an explicit error type carry their error as a FinchString.
+          let (result_type, err_body) = match original_err_type {
+            Some(original_err_type) => (
+              format!("FinchResult<{}, {}>", original_inner_type.display_name, original_err_type.display_name),
+              original_err_type.convert_ret(ctx, "finch.err._0".to_string())?,
+            ),
+            None => (
+              format!("FinchResult<{}>", original_inner_type.display_name),
+              format!("
+                [](finch::bindgen::{crate_name}::FinchString finch) -> std::string {{
+                  std::string str(finch.ptr, finch.len);
+                  finch::bindgen::{crate_name}::___finch_bindgen___{crate_name}___builtin___FinchString___drop(finch);
+                  return str;
+                }}(finch.err._0)",
+                crate_name=ctx.crate_name,
+              ),
+            ),
+          };
   
-          TEMPLATES.lock().unwrap().insert(format!("template struct FinchResult<{}>;", original_inner_type.display_name));
+          ctx.templates.insert(format!("template struct {};", result_type));
+
+          if ctx.expected_results {
+            ctx.use_expected = true;
+            let err_type = match original_err_type {
+              Some(x) => x.to_cpp_type(ctx)?,
+              None => "std::string".to_string(),
+            };
+
+            return Ok(format!("
+              [](finch::bindgen::{crate_name}::{result_type} finch) -> finch::expected<{inner_type}, {err_type}> {{
+                if (finch.tag == finch::bindgen::{crate_name}::{result_type}::Tag::Ok) {{
+                  return finch::expected<{inner_type}, {err_type}>({inner_body});
+                }} else {{
+                  return finch::unexpected<{err_type}>({err_body});
+                }}
+              }}({body})",
+              crate_name=ctx.crate_name,
+              result_type=result_type,
+              inner_type=inner_type,
+              err_type=err_type,
+              inner_body=inner_body,
+              err_body=err_body,
+              body=body,
+            ));
+          }
+
+          let err_body = match original_err_type {
+            Some(original_err_type) => raise_error(ctx, original_err_type, err_body)?,
+            None => format!("Exception::raise({});", err_body),
+          };
   
-          format!(r#"
-            [](finch::bindgen::{crate_name}::FinchResult<{original_inner_type}> finch) -> {inner_type} {{
-              if (finch.tag == finch::bindgen::{crate_name}::FinchResult<{original_inner_type}>::Tag::Ok) {{
+          format!("
+            [](finch::bindgen::{crate_name}::{result_type} finch) -> {inner_type} {{
+              if (finch.tag == finch::bindgen::{crate_name}::{result_type}::Tag::Ok) {{
                 return {inner_body};
               }} else {{
-                std::string str(finch.err._0.ptr, finch.err._0.len);
-                finch::bindgen::{crate_name}::___finch_bindgen___{crate_name}___builtin___FinchString___drop(finch.err._0);
-                #ifdef finch_bindgen_EXCEPTIONS
-                  throw std::runtime_error(str);
-                #else
-                  std::cout << "fatal: Result returned Err(\"" << str << "\")" << std::endl;
-                  abort();
-                #endif
+                {err_body}
+              }}
+            }}({body})",
+            crate_name=ctx.crate_name,
+            result_type=result_type,
+            inner_type=inner_type,
+            inner_body=inner_body,
+            err_body=err_body,
+            body=body,
+          )
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchVec", ctx.crate_name).as_str()) {
+          ctx.use_vector = true;
+          let original_inner_type = template_argument(self, 0)?;
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+          let inner_body = original_inner_type.convert_ret(ctx, "finch.ptr[i]".to_string())?;
+
+          ctx.templates.insert(format!("template struct FinchVec<{}>;", original_inner_type.display_name));
+
+          // Every element is moved out of the buffer by its own conversion, so only
+          // the allocation itself is left for the Rust side to free.
+          format!("
+            [](finch::bindgen::{crate_name}::FinchVec<{original_inner_type}> finch) -> std::vector<{inner_type}> {{
+              std::vector<{inner_type}> vec;
+              vec.reserve(finch.len);
+              for (size_t i = 0; i < finch.len; i++) {{
+                vec.push_back({inner_body});
               }}
-            }}({body})"#,
-            crate_name=unsafe { &CRATE_NAME },
+              finch::bindgen::{crate_name}::___finch_bindgen___{crate_name}___builtin___FinchVec___drop(finch.ptr, finch.cap, sizeof({original_inner_type}), alignof({original_inner_type}));
+              return vec;
+            }}({body})",
+            crate_name=ctx.crate_name,
             original_inner_type=original_inner_type.display_name,
             inner_type=inner_type,
             inner_body=inner_body,
             body=body,
           )
+        } else if self.display_name.starts_with(format!("finch::bindgen::{}::FinchSlice", ctx.crate_name).as_str()) {
+          let original_inner_type = template_argument(self, 0)?;
+          ctx.use_vector = true;
+
+          // The elements still belong to Rust, so they are copied rather than
+          // converted, which would free them.
+          if !is_primitive(original_inner_type) {
+            let inner_type = original_inner_type.to_cpp_type(ctx)?;
+            let inner_body = copy_borrowed_element(ctx, original_inner_type, "finch.ptr[i]".to_string())?;
+
+            ctx.templates.insert(format!("template struct FinchSlice<{}>;", original_inner_type.display_name));
+
+            return Ok(format!("
+              [](finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> finch) -> std::vector<{inner_type}> {{
+                std::vector<{inner_type}> vec;
+                vec.reserve(finch.len);
+                for (size_t i = 0; i < finch.len; i++) {{
+                  vec.push_back({inner_body});
+                }}
+                return vec;
+              }}({body})",
+              crate_name=ctx.crate_name,
+              original_inner_type=original_inner_type.display_name,
+              inner_type=inner_type,
+              inner_body=inner_body,
+              body=body,
+            ));
+          }
+
+          ctx.use_slice = true;
+          let inner_type = original_inner_type.to_cpp_type(ctx)?;
+
+          ctx.templates.insert(format!("template struct FinchSlice<{}>;", original_inner_type.display_name));
+
+          format!("
+            [](finch::bindgen::{crate_name}::FinchSlice<{original_inner_type}> finch) -> {slice} {{
+              return {slice}(finch.ptr, finch.len);
+            }}({body})",
+            slice=ctx.slice(&inner_type),
+            crate_name=ctx.crate_name,
+            original_inner_type=original_inner_type.display_name,
+            body=body,
+          )
         } else {
-          panic!("unknown type {}", self.display_name)
+          return Err(Unsupported(self.display_name.clone()))
         }
       },
   
-      _ => panic!("unknown type {}", self.display_name)
+      _ => return Err(Unsupported(self.display_name.clone())),
+    })
+  }
+}
+
+/// The class `type_` points to, as generated code refers to it.
+fn class_name(ctx: &Context, type_: &FinchType) -> Option<String> {
+  let display_name = type_.display_name.trim_start_matches("const ");
+  if display_name.starts_with(&format!("finch::bindgen::{}::___finch_bindgen___{0}___class___", ctx.crate_name)) {
+    Some(ctx.qualified(display_name.split("___").collect::<Vec<&str>>()[4].trim_end_matches('*').trim_end()))
+  } else {
+    None
+  }
+}
+
+/// How Rust takes a class argument.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ClassArg {
+  /// `const T *`, a `&T`.
+  Shared,
+  /// `T *`, a `&mut T`. The object stays owned by C++.
+  Mutable,
+  /// `T` by value, the object is moved into Rust.
+  Owned,
+}
+
+fn class_arg(type_: &FinchType) -> ClassArg {
+  if type_.display_name.starts_with("const ") {
+    ClassArg::Shared
+  } else if type_.kind == TypeKind::Pointer || type_.display_name.ends_with('*') {
+    ClassArg::Mutable
+  } else {
+    ClassArg::Owned
+  }
+}
+
+/// Moves the internal pointer out of an object handed over to Rust.
+fn take_object(class: &str, body: &str) -> String {
+  format!("
+    []({} &value) -> decltype(value.self) {{
+      assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", value.self != nullptr));
+      auto self = value.self;
+      value.self = nullptr;
+      return self;
+    }}({})",
+    class,
+    body,
+  )
+}
+
+/// Converts an element held by a container, which stores classes by pointer.
+/// Unless that pointer is `const`, the container owns the object and it is
+/// moved into Rust along with it.
+fn convert_element_arg(ctx: &mut Context, type_: &FinchType, body: String) -> Result<String, Unsupported> {
+  if let Some(class) = class_name(ctx, type_) {
+    return Ok(match class_arg(type_) {
+      ClassArg::Shared => format!("{}->self", body),
+      _ => take_object(&class, &format!("*{}", body)),
+    });
+  }
+
+  type_.convert_arg(ctx, body)
+}
+
+/// The `index`th template argument of a bindgen container type.
+fn template_argument(type_: &FinchType, index: usize) -> Result<&FinchType, Unsupported> {
+  type_.template_argument_types.as_ref()
+    .and_then(|x| x.get(index))
+    .and_then(|x| x.as_ref())
+    .ok_or_else(|| Unsupported(type_.display_name.clone()))
+}
+
+fn is_primitive(type_: &FinchType) -> bool {
+  if let Some(canonical_type) = type_.canonical_type.as_ref() {
+    return is_primitive(canonical_type);
+  }
+
+  matches!(type_.kind,
+    TypeKind::Bool |
+    TypeKind::CharS | TypeKind::CharU | TypeKind::SChar | TypeKind::UChar |
+    TypeKind::Short | TypeKind::UShort | TypeKind::Int | TypeKind::UInt |
+    TypeKind::Long | TypeKind::ULong | TypeKind::LongLong | TypeKind::ULongLong |
+    TypeKind::Float | TypeKind::Double
+  )
+}
+
+/// Builds the statement that throws (or aborts with) the converted error of a `FinchResult`.
+fn raise_error(ctx: &mut Context, type_: &FinchType, body: String) -> Result<String, Unsupported> {
+  if let Some(enum_) = find_enum(ctx, &type_.display_name) {
+    ctx.errors.insert(enum_.c_name.clone());
+    return Ok(format!("{}Exception::raise({});", ctx.qualified(&enum_.name), body));
+  }
+
+  if let Some(canonical_type) = type_.canonical_type.as_ref() {
+    return raise_error(ctx, canonical_type, body);
+  }
+
+  if type_.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
+    Ok(format!("Exception::raise({});", body))
+  } else {
+    Ok(format!("TypedException<{}>::raise({});", type_.to_cpp_type(ctx)?, body))
+  }
+}
+
+fn find_enum(ctx: &Context, display_name: &str) -> Option<FinchEnum> {
+  ctx.enums.get(display_name).cloned()
+}
+
+fn is_fieldless(enum_: &FinchEnum) -> bool {
+  enum_.variants.iter().all(|x| x.field_types.is_empty())
+}
+
+/// Copies an element of a slice returned by Rust into its C++ type, leaving the
+/// borrowed original alone. Classes can't be copied out of a slice.
+fn copy_borrowed_element(ctx: &mut Context, type_: &FinchType, body: String) -> Result<String, Unsupported> {
+  if class_name(ctx, type_).is_some() {
+    return Err(Unsupported(type_.display_name.clone()));
+  }
+
+  if let Some(enum_) = find_enum(ctx, &type_.display_name) {
+    if is_fieldless(&enum_) {
+      return Ok(format!("static_cast<{}>({})", ctx.qualified(&enum_.name), body));
+    }
+    return Err(Unsupported(type_.display_name.clone()));
+  }
+
+  if let Some(canonical_type) = type_.canonical_type.as_ref() {
+    return copy_borrowed_element(ctx, canonical_type, body);
+  }
+
+  if is_primitive(type_) {
+    Ok(body)
+  } else if type_.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
+    Ok(format!("std::string({0}.ptr, {0}.len)", body))
+  } else if type_.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", ctx.crate_name).as_str()) {
+    ctx.use_optional = true;
+    let original_inner_type = template_argument(type_, 0)?;
+    let inner_type = original_inner_type.to_cpp_type(ctx)?;
+    let inner_body = copy_borrowed_element(ctx, original_inner_type, "finch.some._0".to_string())?;
+
+    ctx.templates.insert(format!("template struct FinchOption<{}>;", original_inner_type.display_name));
+
+    Ok(format!("
+      [](const finch::bindgen::{crate_name}::FinchOption<{original_inner_type}> &finch) -> {optional}<{inner_type}> {{
+        if (finch.tag == finch::bindgen::{crate_name}::FinchOption<{original_inner_type}>::Tag::Some) {{
+          return {optional}<{inner_type}>({inner_body});
+        }} else {{
+          return {nullopt};
+        }}
+      }}({body})",
+      optional=ctx.optional(),
+      nullopt=ctx.nullopt(),
+      crate_name=ctx.crate_name,
+      original_inner_type=original_inner_type.display_name,
+      inner_type=inner_type,
+      inner_body=inner_body,
+      body=body,
+    ))
+  } else {
+    Err(Unsupported(type_.display_name.clone()))
+  }
+}
+
+/// Frees an element converted for a borrowed slice once the call returned, as
+/// Rust doesn't take ownership of it. Elements whose conversion moves an object
+/// into Rust can't be borrowed and are unsupported.
+fn borrowed_element_drop(ctx: &Context, type_: &FinchType, body: &str) -> Result<String, Unsupported> {
+  if class_name(ctx, type_).is_some() {
+    return match class_arg(type_) {
+      ClassArg::Shared => Ok("".to_string()),
+      _ => Err(Unsupported(type_.display_name.clone())),
+    };
+  }
+
+  if let Some(enum_) = find_enum(ctx, &type_.display_name) {
+    if is_fieldless(&enum_) {
+      return Ok("".to_string());
+    }
+    return Err(Unsupported(type_.display_name.clone()));
+  }
+
+  if let Some(canonical_type) = type_.canonical_type.as_ref() {
+    return borrowed_element_drop(ctx, canonical_type, body);
+  }
+
+  if is_primitive(type_) {
+    Ok("".to_string())
+  } else if type_.display_name == format!("finch::bindgen::{}::FinchString", ctx.crate_name) {
+    Ok(format!("finch::bindgen::{0}::___finch_bindgen___{0}___builtin___FinchString___drop({1});", ctx.crate_name, body))
+  } else {
+    Err(Unsupported(type_.display_name.clone()))
+  }
+}
+
+/// Words C++ reserves, which can't name a namespace. Alternative operator
+/// tokens like `and` are included as they can't be used as names either.
+const CPP_KEYWORDS: &[&str] = &[
+  "alignas", "alignof", "and", "and_eq", "asm", "auto", "bitand", "bitor", "bool", "break", "case",
+  "catch", "char", "char8_t", "char16_t", "char32_t", "class", "compl", "concept", "const",
+  "consteval", "constexpr", "constinit", "const_cast", "continue", "co_await", "co_return",
+  "co_yield", "decltype", "default", "delete", "do", "double", "dynamic_cast", "else", "enum",
+  "explicit", "export", "extern", "false", "float", "for", "friend", "goto", "if", "inline", "int",
+  "long", "mutable", "namespace", "new", "noexcept", "not", "not_eq", "nullptr", "operator", "or",
+  "or_eq", "private", "protected", "public", "register", "reinterpret_cast", "requires", "return",
+  "short", "signed", "sizeof", "static", "static_assert", "static_cast", "struct", "switch",
+  "template", "this", "thread_local", "throw", "true", "try", "typedef", "typeid", "typename",
+  "union", "unsigned", "using", "virtual", "void", "volatile", "wchar_t", "while", "xor", "xor_eq",
+];
+
+/// Whether `name` can be declared in C++, an ASCII identifier that isn't a
+/// keyword.
+fn is_identifier(name: &str) -> bool {
+  let mut chars = name.chars();
+  chars.next().is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+    && chars.all(|x| x.is_ascii_alphanumeric() || x == '_')
+    && !CPP_KEYWORDS.contains(&name)
+}
+
+/// Matches the naming cbindgen uses for the union members of tagged enums.
+fn to_snake_case(name: &str) -> String {
+  let mut s = String::new();
+  for (i, c) in name.char_indices() {
+    if c.is_uppercase() && i != 0 {
+      s.push('_');
     }
+    s.extend(c.to_lowercase());
   }
+  s
 }
 
 trait ToCPP {
-  fn to_header(&self) -> String;
-  fn to_impl(&self) -> String;
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error>;
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error>;
 }
 
 impl ToCPP for FinchNew {
-  fn to_header(&self) -> String {
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
     let mut args = Vec::new();
     for (i, name) in self.arg_names.iter().enumerate() {
-      args.push(format!("{} {}", self.arg_types[i].to_cpp_type(), name));
+      args.push(format!("{} {}", self.arg_types[i].to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, "new", Position::Argument(i)))?, name));
     }
 
-    format!("
+    Ok(format!("
       {}
       {}({});",
       self.comments.as_ref().unwrap_or(&"".to_string()),
       self.class_name,
       args.join(", ")
-    )
+    ))
   }
 
-  fn to_impl(&self) -> String {
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
     let mut args = Vec::new();
     for (i, name) in self.arg_names.iter().enumerate() {
-      args.push(format!("{} {}", self.arg_types[i].to_cpp_type(), name));
+      args.push(format!("{} {}", self.arg_types[i].to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, "new", Position::Argument(i)))?, name));
     }
 
-    format!("
-      {}::{0}({}) {{
+    Ok(format!("
+      {}{}::{1}({}) {{
         this->self = {}({});
       }}",
+      ctx.inline(),
       self.class_name,
       args.join(", "),
       self.fn_name,
       self.arg_names.iter().enumerate().map(|(i, x)| {
-        self.arg_types[i].convert_arg(x.clone())
-      }).collect::<Vec<String>>().join(", "),
-    )
+        self.arg_types[i].convert_arg(ctx, x.clone()).map_err(|e| e.at(&self.class_name, "new", Position::Argument(i)))
+      }).collect::<Result<Vec<String>, _>>()?.join(", "),
+    ))
   }
 }
 
 impl ToCPP for FinchDrop {
-  fn to_header(&self) -> String {
-    format!("  ~{}();", self.class_name)
+  fn to_header(&self, _ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("  ~{}();", self.class_name))
   }
 
-  fn to_impl(&self) -> String {
-    format!("
-      {}::~{0}() {{
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
+      {}{}::~{1}() {{
         if (this->self) {{
           {}(this->self);
         }}
       }}",
+      ctx.inline(),
       self.class_name,
       self.fn_name,
-    )
+    ))
   }
 }
 
-impl ToCPP for FinchMethod {
-  fn to_header(&self) -> String {
-    let mut args = Vec::new();
-    for (i, name) in self.arg_names.iter().enumerate() {
-      args.push(format!("{} {}", self.arg_types[i].to_cpp_type(), name));
-    }
-
-    format!("
-      {}
-      {} {}({});",
-      self.comments.as_ref().unwrap_or(&"".to_string()),
-      self.ret_type.to_cpp_type(),
-      self.method_name,
-      args.join(", ")
-    )
+impl ToCPP for FinchClone {
+  fn to_header(&self, _ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
+      {0}(const {0} &other);
+      {0} &operator=(const {0} &other);",
+      self.class_name,
+    ))
   }
 
-  fn to_impl(&self) -> String {
-    let mut args = Vec::new();
-    for (i, name) in self.arg_names.iter().enumerate() {
-      args.push(format!("{} {}", self.arg_types[i].to_cpp_type(), name));
-    }
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
+      {2}{0}::{0}(const {0} &other) {{
+        this->self = other.self ? {1}(other.self) : nullptr;
+      }}
 
-    let mut s = format!("
-      {} {}::{}({}) {{
-        assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", this->self != nullptr));",
-      self.ret_type.to_cpp_type(),
+      {2}{0} &{0}::operator=(const {0} &other) {{
+        if (this != &other) {{
+          *this = {0}(other);
+        }}
+        return *this;
+      }}",
       self.class_name,
-      self.method_name,
-      args.join(", "),
-    );
+      self.fn_name,
+      ctx.inline(),
+    ))
+  }
+}
 
-    if self.consume {
-      if self.ret_type.kind == TypeKind::Void {
+// A PartialOrd function returns -1 for less, 0 for equal and 1 for greater.
+// Any other value stands for `None` from `partial_cmp`, the values are
+// unordered and every comparison is false.
+impl ToCPP for FinchOperator {
+  fn to_header(&self, _ctx: &mut Context) -> Result<String, Error> {
+    Ok(match self.kind {
+      FinchOperatorKind::PartialEq => format!("
+        bool operator==(const {0} &other) const;
+        bool operator!=(const {0} &other) const;",
+        self.class_name,
+      ),
+
+      FinchOperatorKind::PartialOrd => format!("
+        bool operator<(const {0} &other) const;
+        bool operator<=(const {0} &other) const;
+        bool operator>(const {0} &other) const;
+        bool operator>=(const {0} &other) const;",
+        self.class_name,
+      ),
+
+      FinchOperatorKind::Display | FinchOperatorKind::Debug => format!("
+        friend std::ostream &operator<<(std::ostream &os, const {0} &value);",
+        self.class_name,
+      ),
+
+      FinchOperatorKind::Hash => "".to_string(),
+    })
+  }
+
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    let assert = "assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", this->self != nullptr && other.self != nullptr));";
+
+    Ok(match self.kind {
+      FinchOperatorKind::PartialEq => format!("
+        {3}bool {0}::operator==(const {0} &other) const {{
+          {2}
+          return {1}(this->self, other.self);
+        }}
+
+        {3}bool {0}::operator!=(const {0} &other) const {{
+          return !(*this == other);
+        }}",
+        self.class_name,
+        self.fn_name,
+        assert,
+        ctx.inline(),
+      ),
+
+      FinchOperatorKind::PartialOrd => ["<", "<=", ">", ">="].iter().map(|op| {
+        let condition = match *op {
+          "<" => "ordering == -1",
+          "<=" => "ordering == -1 || ordering == 0",
+          ">" => "ordering == 1",
+          _ => "ordering == 1 || ordering == 0",
+        };
+
+        format!("
+          {5}bool {0}::operator{1}(const {0} &other) const {{
+            {4}
+            auto ordering = {2}(this->self, other.self);
+            return {3};
+          }}",
+          self.class_name,
+          op,
+          self.fn_name,
+          condition,
+          assert,
+          ctx.inline(),
+        )
+      }).collect::<Vec<String>>().join("\n"),
+
+      FinchOperatorKind::Display | FinchOperatorKind::Debug => format!("
+        {}std::ostream &operator<<(std::ostream &os, const {} &value) {{
+          assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", value.self != nullptr));
+          auto finch = {}(value.self);
+          os << std::string(finch.ptr, finch.len);
+          finch::bindgen::{crate_name}::___finch_bindgen___{crate_name}___builtin___FinchString___drop(finch);
+          return os;
+        }}",
+        ctx.inline(),
+        self.class_name,
+        self.fn_name,
+        crate_name=ctx.crate_name,
+      ),
+
+      FinchOperatorKind::Hash => "".to_string(),
+    })
+  }
+}
+
+// `&self` methods are const and methods taking `self` can only be called on
+// rvalues, so a consumed object has to be explicitly moved from.
+fn method_qualifier(method: &FinchMethod) -> &'static str {
+  if method.consume {
+    " &&"
+  } else if method.mutable {
+    ""
+  } else {
+    " const"
+  }
+}
+
+impl ToCPP for FinchMethod {
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
+    let mut args = Vec::new();
+    for (i, name) in self.arg_names.iter().enumerate() {
+      args.push(format!("{} {}", self.arg_types[i].to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))?, name));
+    }
+
+    Ok(format!("
+      {}
+      {} {}({}){};",
+      self.comments.as_ref().unwrap_or(&"".to_string()),
+      self.ret_type.to_cpp_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?,
+      self.method_name,
+      args.join(", "),
+      method_qualifier(self),
+    ))
+  }
+
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    let mut args = Vec::new();
+    for (i, name) in self.arg_names.iter().enumerate() {
+      args.push(format!("{} {}", self.arg_types[i].to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))?, name));
+    }
+
+    let mut s = format!("
+      {}{} {}::{}({}){} {{
+        assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", this->self != nullptr));",
+      ctx.inline(),
+      self.ret_type.to_cpp_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?,
+      self.class_name,
+      self.method_name,
+      args.join(", "),
+      method_qualifier(self),
+    );
+
+    if self.consume {
+      if self.ret_type.kind == TypeKind::Void {
         s += format!("
           {}(this->self{});
             this->self = nullptr;
           }}",
           self.fn_name,
           self.arg_names.iter().enumerate().map(|(i, x)| {
-            self.arg_types[i].convert_arg(x.clone())
-          }).collect::<Vec<String>>().join(", "),
+            self.arg_types[i].convert_arg(ctx, x.clone()).map(|x| format!(", {}", x)).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))
+          }).collect::<Result<Vec<String>, _>>()?.join(""),
         ).as_str();
       } else {
-        let body = self.ret_type.convert_ret("value".to_string());
+        let body = self.ret_type.convert_ret(ctx, "value".to_string()).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?;
         s += format!("
             auto value = {}(this->self{});
             this->self = nullptr;
@@ -329,339 +1461,2938 @@ impl ToCPP for FinchMethod {
           }}",
           self.fn_name,
           self.arg_names.iter().enumerate().map(|(i, x)| {
-            self.arg_types[i].convert_arg(x.clone())
-          }).collect::<Vec<String>>().join(", "),
+            self.arg_types[i].convert_arg(ctx, x.clone()).map(|x| format!(", {}", x)).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))
+          }).collect::<Result<Vec<String>, _>>()?.join(""),
           body
         ).as_str();
       }
     } else {
-      let body = self.ret_type.convert_ret(format!("{}(this->self{})", self.fn_name, self.arg_names.iter().enumerate().map(|(i, x)| {
-        self.arg_types[i].convert_arg(x.clone())
-      }).collect::<Vec<String>>().join(", ")));
+      // The arguments follow `self`, each with its own leading comma.
+      let args = self.arg_names.iter().enumerate().map(|(i, x)| {
+        self.arg_types[i].convert_arg(ctx, x.clone()).map(|x| format!(", {}", x)).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))
+      }).collect::<Result<Vec<String>, _>>()?.join("");
+      let body = self.ret_type.convert_ret(ctx, format!("{}(this->self{})", self.fn_name, args)).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?;
 
       s += format!("
-          {};
+          return {};
         }}",
         body,
       ).as_str();
     }
 
-    s
+    Ok(s)
   }
 }
 
 impl ToCPP for FinchStatic {
-  fn to_header(&self) -> String {
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
     let mut args = Vec::new();
     for (i, name) in self.arg_names.iter().enumerate() {
-      args.push(format!("{} {}", self.arg_types[i].to_cpp_type(), name));
+      args.push(format!("{} {}", self.arg_types[i].to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))?, name));
     }
 
-    format!("
+    Ok(format!("
       {}
       static {} {}({});",
       self.comments.as_ref().unwrap_or(&"".to_string()),
-      self.ret_type.to_cpp_type(),
+      self.ret_type.to_cpp_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?,
       self.method_name,
       args.join(", ")
-    )
+    ))
   }
 
-  fn to_impl(&self) -> String {
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
     let mut args = Vec::new();
     for (i, name) in self.arg_names.iter().enumerate() {
-      args.push(format!("{} {}", self.arg_types[i].to_cpp_type(), name));
+      args.push(format!("{} {}", self.arg_types[i].to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))?, name));
     }
 
-    let body = self.ret_type.convert_ret(format!("{}({})", self.fn_name, self.arg_names.iter().enumerate().map(|(i, x)| {
-      self.arg_types[i].convert_arg(x.clone())
-    }).collect::<Vec<String>>().join(", ")));
+    let call_args = self.arg_names.iter().enumerate().map(|(i, x)| {
+      self.arg_types[i].convert_arg(ctx, x.clone()).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Argument(i)))
+    }).collect::<Result<Vec<String>, _>>()?.join(", ");
+    let body = self.ret_type.convert_ret(ctx, format!("{}({})", self.fn_name, call_args)).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?;
 
-    format!("
-      {} {}::{}({}) {{
+    Ok(format!("
+      {}{} {}::{}({}) {{
         return {};
       }}",
-      self.ret_type.to_cpp_type(),
+      ctx.inline(),
+      self.ret_type.to_cpp_type(ctx).map_err(|e| e.at(&self.class_name, &self.method_name, Position::Return))?,
       self.class_name,
       self.method_name,
       args.join(", "),
       body,
-    )
+    ))
   }
 }
 
 impl ToCPP for FinchGetter {
-  fn to_header(&self) -> String {
-    format!("
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
       {}
-      {} get_{}();",
+      {} get_{}() const;",
       self.comments.as_ref().unwrap_or(&"".to_string()),
-      self.type_.to_cpp_type(),
-      self.field_name)
+      self.type_.to_cpp_type(ctx).map_err(|e| e.at(&self.class_name, &self.field_name, Position::Return))?,
+      self.field_name))
   }
 
-  fn to_impl(&self) -> String {
-    let body = self.type_.convert_ret(format!("{}(this->self)", self.fn_name));
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    let body = self.type_.convert_ret(ctx, format!("{}(this->self)", self.fn_name)).map_err(|e| e.at(&self.class_name, &self.field_name, Position::Return))?;
 
-    format!("
-      {} {}::get_{}() {{
+    Ok(format!("
+      {}{} {}::get_{}() const {{
         assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", this->self != nullptr));
         return {};
       }}",
-      self.type_.to_cpp_type(),
+      ctx.inline(),
+      self.type_.to_cpp_type(ctx).map_err(|e| e.at(&self.class_name, &self.field_name, Position::Return))?,
       self.class_name,
       self.field_name,
       body,
-    )
+    ))
   }
 }
 
 impl ToCPP for FinchSetter {
-  fn to_header(&self) -> String {
-    format!("
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
       {}
       void set_{}({} value);",
       self.comments.as_ref().unwrap_or(&"".to_string()),
       self.field_name,
-      self.type_.to_cpp_type(),
-    )
+      self.type_.to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, &self.field_name, Position::Argument(0)))?,
+    ))
   }
 
-  fn to_impl(&self) -> String {
-    let body = self.type_.convert_arg("value".to_string());
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    let body = self.type_.convert_arg(ctx, "value".to_string()).map_err(|e| e.at(&self.class_name, &self.field_name, Position::Argument(0)))?;
 
-    format!("
-      void {}::set_{}({} value) {{
+    Ok(format!("
+      {}void {}::set_{}({} value) {{
         assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", this->self != nullptr));
         return {}(this->self, {});
       }}",
+      ctx.inline(),
       self.class_name,
       self.field_name,
-      self.type_.to_cpp_type(),
+      self.type_.to_cpp_arg_type(ctx).map_err(|e| e.at(&self.class_name, &self.field_name, Position::Argument(0)))?,
       self.fn_name,
       body,
-    )
+    ))
   }
 }
 
 impl ToCPP for FinchClass {
-  fn to_header(&self) -> String {
-    format!("
-      {}
-      class {} {{
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
+      {comments}
+      class {name} {{
       public:
-      {}
-      {}
+      {new}
+      {drop}
 
-      {}
+      {statics}
 
-      {}
+      {methods}
 
-      {}
+      {getters}
 
-      {}
+      {setters}
+
+      {operators}
 
-      {1}({8} *self) {{
+      {range}
+
+      {name}({c_name} *self) {{
         this->self = self;
       }}
-      {} *self = nullptr;
-
-      private:
-        {1}(const {1}&) = delete;
-        {1} &operator=(const {1}&) = delete;
+      {name}({name} &&other) noexcept;
+      {name} &operator=({name} &&other) noexcept;
+      {clone}
+      {c_name} *self = nullptr;
+      {private}
       }};",
-      self.comments.as_ref().unwrap_or(&"".to_string()),
-      self.name,
-      self.new.as_ref().map_or("".to_string(), |x| x.to_header()),
-      self.drop.as_ref().map_or("".to_string(), |x| x.to_header()),
-      self.statics.iter().map(|x| x.to_header()).collect::<Vec<String>>().join("\n\n"),
-      self.methods.iter().map(|x| x.to_header()).collect::<Vec<String>>().join("\n\n"),
-      self.getters.iter().map(|x| x.to_header()).collect::<Vec<String>>().join("\n\n"),
-      self.setters.iter().map(|x| x.to_header()).collect::<Vec<String>>().join("\n\n"),
-      self.c_name,
-    )
+      comments=self.comments.as_ref().unwrap_or(&"".to_string()),
+      name=self.name,
+      new=self.new.as_ref().map(|x| x.to_header(ctx)).transpose()?.unwrap_or_default(),
+      drop=self.drop.as_ref().map(|x| x.to_header(ctx)).transpose()?.unwrap_or_default(),
+      statics=self.statics.iter().map(|x| x.to_header(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n\n"),
+      methods=self.methods.iter().map(|x| x.to_header(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n\n"),
+      getters=self.getters.iter().map(|x| x.to_header(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n\n"),
+      setters=self.setters.iter().map(|x| x.to_header(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n\n"),
+      operators=self.operators().iter().map(|x| x.to_header(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n\n"),
+      range=to_range_header(ctx, self)?,
+      clone=self.clone.as_ref().map(|x| x.to_header(ctx)).transpose()?.unwrap_or_default(),
+      c_name=self.c_name,
+      private=if self.clone.is_some() {
+        "".to_string()
+      } else {
+        format!("
+          private:
+            {0}(const {0}&) = delete;
+            {0} &operator=(const {0}&) = delete;",
+          self.name,
+        )
+      },
+    ))
   }
 
-  fn to_impl(&self) -> String {
-    format!("
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    Ok(format!("
+      {}
+      {}
+      {inline}{}::{2}({2} &&other) noexcept {{
+        this->self = other.self;
+        other.self = nullptr;
+      }}
+
+      {inline}{2} &{2}::operator=({2} &&other) noexcept {{
+        if (this != &other) {{
+          {}
+          this->self = other.self;
+          other.self = nullptr;
+        }}
+        return *this;
+      }}
+      {}
       {}
       {}
       {}
       {}
       {}
       {}",
-      self.new.as_ref().map_or("".to_string(), |x| x.to_impl()),
-      self.drop.as_ref().map_or("".to_string(), |x| x.to_impl()),
-      self.statics.iter().map(|x| x.to_impl()).collect::<Vec<String>>().join("\n"),
-      self.methods.iter().map(|x| x.to_impl()).collect::<Vec<String>>().join("\n"),
-      self.getters.iter().map(|x| x.to_impl()).collect::<Vec<String>>().join("\n"),
-      self.setters.iter().map(|x| x.to_impl()).collect::<Vec<String>>().join("\n"),
-    )
+      self.new.as_ref().map(|x| x.to_impl(ctx)).transpose()?.unwrap_or_default(),
+      self.drop.as_ref().map(|x| x.to_impl(ctx)).transpose()?.unwrap_or_default(),
+      self.name,
+      self.drop.as_ref().map_or("".to_string(), |x| format!("
+        if (this->self) {{
+          {}(this->self);
+        }}",
+        x.fn_name,
+      )),
+      self.clone.as_ref().map(|x| x.to_impl(ctx)).transpose()?.unwrap_or_default(),
+      self.operators().iter().map(|x| x.to_impl(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n"),
+      to_range_impl(ctx, self)?,
+      self.statics.iter().map(|x| x.to_impl(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n"),
+      self.methods.iter().map(|x| x.to_impl(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n"),
+      self.getters.iter().map(|x| x.to_impl(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n"),
+      self.setters.iter().map(|x| x.to_impl(ctx)).collect::<Result<Vec<String>, _>>()?.join("\n"),
+      inline=ctx.inline(),
+    ))
   }
 }
 
-fn copy_third_party(config: &Config) -> Result<(), Box<dyn Error>> {
-  if unsafe { USE_OPTIONAL } {
-    let mut file = File::create(config.out_dir.join("include").join("optional.h"))?;
+impl ToCPP for FinchEnum {
+  fn to_header(&self, ctx: &mut Context) -> Result<String, Error> {
+    if is_fieldless(self) {
+      return Ok(format!("
+        {}
+        enum class {} : std::underlying_type<{}>::type {{
+          {}
+        }};",
+        self.comments.as_ref().unwrap_or(&"".to_string()),
+        self.name,
+        self.c_name,
+        self.variants.iter().map(|x| {
+          format!("
+            {}
+            {} = static_cast<std::underlying_type<{}>::type>({2}::{1}),",
+            x.comments.as_ref().unwrap_or(&"".to_string()),
+            x.name,
+            self.c_name,
+          )
+        }).collect::<Vec<String>>().join("\n"),
+      ));
+    }
+
+    let mut structs = Vec::new();
+    let mut constructors = Vec::new();
+    let mut accessors = Vec::new();
+    let mut members = Vec::new();
+    for (i, variant) in self.variants.iter().enumerate() {
+      let snake_name = to_snake_case(&variant.name);
+
+      structs.push(format!("
+        {}
+        struct {} {{
+          {}
+        }};",
+        variant.comments.as_ref().unwrap_or(&"".to_string()),
+        variant.name,
+        variant.field_names.iter().enumerate().map(|(i, x)| {
+          let type_ = variant.field_types[i].to_cpp_type(ctx).map_err(|e| e.at(&self.name, &variant.name, Position::Field(i)))?;
+          Ok(format!("{} {};", type_, x))
+        }).collect::<Result<Vec<String>, Error>>()?.join("\n"),
+      ));
+
+      constructors.push(format!("{}({} value);", self.name, variant.name));
+
+      let value = if ctx.cpp_standard >= CppStandard::Cpp17 {
+        format!("std::get<{}>(this->value)", i)
+      } else {
+        format!("this->{}_value", snake_name)
+      };
+
+      accessors.push(format!("
+        bool is_{snake_name}() const {{
+          return this->get_tag() == Tag::{variant};
+        }}
+
+        const {variant} &as_{snake_name}() const {{
+          assert((\"The enum does not hold a {variant} value.\", this->get_tag() == Tag::{variant}));
+          return {value};
+        }}",
+        snake_name=snake_name,
+        variant=variant.name,
+        value=value,
+      ));
+
+      members.push(format!("{} {}_value;", variant.name, snake_name));
+    }
+
+    // From C++17 on std::variant keeps track of the active variant. Before that
+    // a union is used, whose special members are written out by hand and only
+    // defined in the implementation, once the classes in the variants are
+    // complete.
+    let (special_members, storage) = if ctx.cpp_standard >= CppStandard::Cpp17 {
+      ctx.use_variant = true;
+      (
+        "
+          Tag get_tag() const {
+            return static_cast<Tag>(this->value.index());
+          }".to_string(),
+        format!("std::variant<{}> value;", self.variants.iter().map(|x| x.name.clone()).collect::<Vec<String>>().join(", ")),
+      )
+    } else {
+      let copy = if is_copyable_enum(ctx, self) {
+        format!("
+          {0}(const {0} &other);
+          {0} &operator=(const {0} &other);",
+          self.name,
+        )
+      } else {
+        "".to_string()
+      };
+
+      (
+        format!("
+          {0}({0} &&other);
+          {0} &operator=({0} &&other);{1}
+          ~{0}();
+
+          Tag get_tag() const {{
+            return this->tag;
+          }}",
+          self.name,
+          copy,
+        ),
+        format!("
+          Tag tag;
+          union {{
+            {}
+          }};",
+          members.join("\n"),
+        ),
+      )
+    };
+
+    Ok(format!("
+      {}
+      class {} {{
+      public:
+        enum class Tag {{
+          {}
+        }};
+
+        {}
+
+        {}
+        {}
 
-    file.write_all(include_bytes!("../third_party/optional.hpp"))?;
+        {}
+
+      private:
+        {}
+      }};",
+      self.comments.as_ref().unwrap_or(&"".to_string()),
+      self.name,
+      self.variants.iter().map(|x| format!("{},", x.name)).collect::<Vec<String>>().join("\n"),
+      structs.join("\n\n"),
+      constructors.join("\n"),
+      special_members,
+      accessors.join("\n\n"),
+      storage,
+    ))
+  }
+
+  fn to_impl(&self, ctx: &mut Context) -> Result<String, Error> {
+    if is_fieldless(self) {
+      return Ok(String::new());
+    }
+
+    if ctx.cpp_standard >= CppStandard::Cpp17 {
+      return Ok(self.variants.iter().map(|x| {
+        format!("
+          {inline}{name}::{name}({variant} value) : value(std::move(value)) {{}}",
+          inline=ctx.inline(),
+          name=self.name,
+          variant=x.name,
+        )
+      }).collect::<Vec<String>>().join("\n"));
+    }
+
+    // Runs `action` on the active member, which is called `value`.
+    let switch = |action: &dyn Fn(&str, &str) -> String| {
+      format!("
+        switch (this->tag) {{{}
+        }}",
+        self.variants.iter().map(|x| {
+          format!("
+            case Tag::{}:
+              {}
+              break;",
+            x.name,
+            action(&x.name, &format!("{}_value", to_snake_case(&x.name))),
+          )
+        }).collect::<Vec<String>>().join(""),
+      )
+    };
+
+    let mut definitions = self.variants.iter().map(|x| {
+      format!("
+        {inline}{name}::{name}({variant} value) : tag(Tag::{variant}) {{
+          new (&this->{snake_name}_value) {variant}(std::move(value));
+        }}",
+        inline=ctx.inline(),
+        name=self.name,
+        variant=x.name,
+        snake_name=to_snake_case(&x.name),
+      )
+    }).collect::<Vec<String>>();
+
+    definitions.push(format!("
+      {inline}{name}::{name}({name} &&other) : tag(other.tag) {{{move}
+      }}
+
+      {inline}{name} &{name}::operator=({name} &&other) {{
+        if (this != &other) {{
+          this->~{name}();
+          new (this) {name}(std::move(other));
+        }}
+        return *this;
+      }}
+
+      {inline}{name}::~{name}() {{{destroy}
+      }}",
+      inline=ctx.inline(),
+      name=self.name,
+      move=switch(&|variant, member| format!("new (&this->{1}) {0}(std::move(other.{1}));", variant, member)),
+      destroy=switch(&|variant, member| format!("this->{}.~{}();", member, variant)),
+    ));
+
+    if is_copyable_enum(ctx, self) {
+      definitions.push(format!("
+        {inline}{name}::{name}(const {name} &other) : tag(other.tag) {{{copy}
+        }}
+
+        {inline}{name} &{name}::operator=(const {name} &other) {{
+          if (this != &other) {{
+            {name} copy(other);
+            *this = std::move(copy);
+          }}
+          return *this;
+        }}",
+        inline=ctx.inline(),
+        name=self.name,
+        copy=switch(&|variant, member| format!("new (&this->{1}) {0}(other.{1});", variant, member)),
+      ));
+    }
+
+    Ok(definitions.join("\n"))
   }
-  
-  Ok(())
 }
 
-fn generate_cmake(config: &Config) -> Result<(), Box<dyn Error>> {
-  if config.generate_cmake {
-    let mut file = File::create(config.out_dir.join("CMakeLists.txt"))?;
+/// Whether the C++ type of `type_` can be copied, so that a union holding it
+/// can get a copy constructor.
+fn is_copyable(ctx: &Context, type_: &FinchType) -> bool {
+  if class_name(ctx, type_).is_some() {
+    return !ctx.unique_ptr;
+  }
 
-    let abs_include_dir = config.out_dir.join("include");
-    let include_dir = abs_include_dir.strip_prefix(std::env::current_dir().unwrap())?;
+  if let Some(enum_) = find_enum(ctx, &type_.display_name) {
+    return is_copyable_enum(ctx, &enum_);
+  }
 
-    file.write_fmt(format_args!("set(CRATE_NAME \"{}\")\n", get_package_name()?))?;
-    file.write_fmt(format_args!("set({}_INCLUDE_DIR \"${{CMAKE_CURRENT_SOURCE_DIR}}/{}\")\n\n", get_package_name()?, include_dir.display()))?;
-    file.write(include_bytes!("../CMakeLists.txt.in"))?;
+  if let Some(canonical_type) = type_.canonical_type.as_ref() {
+    return is_copyable(ctx, canonical_type);
   }
-  
-  Ok(())
+
+  type_.template_argument_types.iter().flatten().flatten().all(|x| is_copyable(ctx, x))
 }
 
-#[derive(Clone, Debug)]
-pub struct Config {
-  out_dir: PathBuf,
-  generate_cmake: bool,
+fn is_copyable_enum(ctx: &Context, enum_: &FinchEnum) -> bool {
+  enum_.variants.iter().all(|x| x.field_types.iter().all(|x| is_copyable(ctx, x)))
 }
 
-impl Default for Config {
-  fn default() -> Self {
-    Self {
-      out_dir: std::env::current_dir().unwrap(),
-      generate_cmake: true,
-    }
+/// Collects the bindgen names of the enums `type_` holds by value, which have
+/// to be declared before it.
+fn enum_dependencies(ctx: &Context, type_: &FinchType, dependencies: &mut BTreeSet<String>) {
+  if let Some(enum_) = find_enum(ctx, &type_.display_name) {
+    dependencies.insert(enum_.c_name);
+    return;
   }
-}
 
-impl Config {
-  fn to_frontend_cfg(&self) -> finch_frontend_api::Config {
-    finch_frontend_api::Config {
-      out_dir: Some(self.out_dir.join("include")),
-    }
+  if let Some(canonical_type) = type_.canonical_type.as_ref() {
+    enum_dependencies(ctx, canonical_type, dependencies);
   }
 
-  pub fn generate(self) -> Result<(), Box<dyn Error>> {
-    let name = get_package_name()?;
-    let name_underscore = name.replace("-", "_");
+  for argument in type_.template_argument_types.iter().flatten().flatten() {
+    enum_dependencies(ctx, argument, dependencies);
+  }
+}
 
-    unsafe {
-      CRATE_NAME = name_underscore.clone();
+/// Orders `enums` so that every enum comes after the enums its variants hold,
+/// keeping the name order otherwise.
+fn sort_enums(ctx: &Context, enums: Vec<FinchEnum>) -> Vec<FinchEnum> {
+  fn visit(ctx: &Context, enum_: &FinchEnum, visited: &mut HashSet<String>, sorted: &mut Vec<FinchEnum>) {
+    if !visited.insert(enum_.c_name.clone()) {
+      return;
     }
-  
-    let output = self.to_frontend_cfg().generate()?;
-  
-    let header_name = self.out_dir.join("include").join(format!("{}.h", name_underscore));
-    let impl_name = self.out_dir.join("include").join(format!("{}-impl.h", name_underscore));
 
-    let mut header_file = File::create(&header_name)?;
-    let mut impl_file = File::create(&impl_name)?;
-  
-    let mut header_content = String::new();
-    let mut impl_content = String::new();
-    for class in output.classes {
-      header_content += &format!("{}\n", class.1.to_header());
-      impl_content += &format!("{}\n", class.1.to_impl());
+    let mut dependencies = BTreeSet::new();
+    for variant in &enum_.variants {
+      for type_ in &variant.field_types {
+        enum_dependencies(ctx, type_, &mut dependencies);
+      }
     }
 
-    let mut bindgen_file = File::open(self.out_dir.join("include").join(format!("{}-finch_bindgen.h", name_underscore)))?;
-    let mut bindgen_content = String::new();
-    bindgen_file.read_to_string(&mut bindgen_content)?;
-    drop(bindgen_file);
+    for dependency in dependencies {
+      if let Some(dependency) = ctx.enums.get(&dependency) {
+        visit(ctx, dependency, visited, sorted);
+      }
+    }
 
-    let bindgen_content = bindgen_content.replace(
-      "extern \"C\" {",
-      &(TEMPLATES.lock().unwrap().clone().into_iter().collect::<Vec<String>>().join("\n") + "\n\nextern \"C\" {"),
-    );
+    sorted.push(enum_.clone());
+  }
 
-    let mut bindgen_file = File::create(self.out_dir.join("include").join(format!("{}-finch_bindgen.h", name_underscore)))?;
-    bindgen_file.write_all(bindgen_content.as_bytes())?;
+  let mut visited = HashSet::new();
+  let mut sorted = Vec::new();
+  for enum_ in &enums {
+    visit(ctx, enum_, &mut visited, &mut sorted);
+  }
+  sorted
+}
 
-    let mut includes = "
-      #include <cstdarg>
-      #include <cstdint>
-      #include <cstdlib>
-      #include <cassert>
-      #include <new>\n".to_string();
+trait ToCPPOperators {
+  fn operators(&self) -> Vec<&FinchOperator>;
+  fn to_std_hash(&self, ctx: &Context) -> String;
+}
 
-    if unsafe { USE_OPTIONAL } {
-      includes += "#include \"optional.h\"\n";
-    }
+impl ToCPPOperators for FinchClass {
+  // Display takes precedence over Debug for operator<<.
+  fn operators(&self) -> Vec<&FinchOperator> {
+    let has_display = self.operators.iter().any(|x| x.kind == FinchOperatorKind::Display);
+    self.operators.iter().filter(|x| !(has_display && x.kind == FinchOperatorKind::Debug)).collect()
+  }
 
-    header_file.write_fmt(format_args!("
-      #pragma once
-      
-      {}
-  
-      #include \"{}-finch_bindgen.h\"
-  
-      namespace {1} {{\n",
-      includes,
-      name_underscore
-    ))?;
-    
-    impl_file.write_fmt(format_args!("
-      #pragma once
-      
-      {}
-  
-      #if defined(__cpp_exceptions) || defined(__EXCEPTIONS) || defined(_CPPUNWIND)
-        #define finch_bindgen_EXCEPTIONS
+  fn to_std_hash(&self, ctx: &Context) -> String {
+    self.operators.iter().filter(|x| x.kind == FinchOperatorKind::Hash).map(|x| {
+      format!("
+        namespace std {{
+          template <>
+          struct hash<{name}> {{
+            size_t operator()(const {name} &value) const {{
+              assert((\"The internal pointer on this object is no longer valid. Either the destructor or a method that consumes the internal pointer has been called.\", value.self != nullptr));
+              return static_cast<size_t>({fn_name}(value.self));
+            }}
+          }};
+        }}",
+        name=ctx.full_name(&self.name),
+        fn_name=x.fn_name,
+      )
+    }).collect::<Vec<String>>().join("\n")
+  }
+}
+
+/// Returns the item type of a class whose `next()` method yields `FinchOption`s.
+fn iterator_item<'a>(ctx: &Context, class: &'a FinchClass) -> Option<&'a FinchType> {
+  class.methods.iter().find(|x| {
+    x.method_name == "next" &&
+    x.arg_names.is_empty() &&
+    !x.consume &&
+    x.ret_type.display_name.starts_with(format!("finch::bindgen::{}::FinchOption", ctx.crate_name).as_str())
+  }).and_then(|x| template_argument(&x.ret_type, 0).ok())
+}
+
+/// Finds the iterator class and item type backing `begin()`/`end()`, either the
+/// class itself or the class returned by its `iter()` method.
+fn range_source(ctx: &Context, class: &FinchClass) -> Option<(String, FinchType, bool)> {
+  if class.methods.iter().any(|x| x.method_name == "begin" || x.method_name == "end") {
+    return None;
+  }
+
+  let iterators = &ctx.iterators;
+  let name = ctx.qualified(&class.name);
+  if let Some(item) = iterators.get(&name) {
+    return Some((name, item.clone(), false));
+  }
+
+  class.methods.iter()
+    .filter(|x| x.method_name == "iter" && x.arg_names.is_empty() && !x.consume)
+    .find_map(|x| class_name(ctx, &x.ret_type).and_then(|name| iterators.get(&name).map(|item| (name, item.clone(), true))))
+}
+
+fn to_range_header(ctx: &mut Context, class: &FinchClass) -> Result<String, Error> {
+  let (iterator, item, _) = match range_source(ctx, class) {
+    Some(x) => x,
+    None => return Ok("".to_string()),
+  };
+
+  ctx.use_iterator = true;
+  ctx.use_optional = true;
+
+  let item = item.to_cpp_type(ctx).map_err(|e| e.at(&iterator, "next", Position::Return))?;
+  Ok(format!("
+    finch::input_iterator<{0}, {1}> begin();
+    {2} end();",
+    iterator,
+    item,
+    range_sentinel(ctx, &iterator, &item),
+  ))
+}
+
+fn range_sentinel(ctx: &Context, iterator: &str, item: &str) -> String {
+  if ctx.cpp_standard >= CppStandard::Cpp20 {
+    "std::default_sentinel_t".to_string()
+  } else {
+    format!("finch::input_iterator<{}, {}>", iterator, item)
+  }
+}
+
+fn to_range_impl(ctx: &mut Context, class: &FinchClass) -> Result<String, Error> {
+  let (iterator, item, owned) = match range_source(ctx, class) {
+    Some(x) => x,
+    None => return Ok("".to_string()),
+  };
+
+  let source = if owned {
+    format!("std::shared_ptr<{}>(this->iter())", iterator)
+  } else {
+    "this".to_string()
+  };
+
+  let item = item.to_cpp_type(ctx).map_err(|e| e.at(&iterator, "next", Position::Return))?;
+
+  // From C++20 on the range ends at std::default_sentinel, so it is a
+  // std::ranges::input_range even when its items can't be copied.
+  if ctx.cpp_standard >= CppStandard::Cpp20 {
+    return Ok(format!("
+      {inline}finch::input_iterator<{iterator}, {item}> {name}::begin() {{
+        return finch::input_iterator<{iterator}, {item}>({source});
+      }}
+
+      {inline}std::default_sentinel_t {name}::end() {{
+        return std::default_sentinel;
+      }}
+
+      static_assert(std::ranges::input_range<{name}>, \"{name} should be an input range\");",
+      inline=ctx.inline(),
+      name=class.name,
+      item=item,
+      iterator=iterator,
+      source=source,
+    ));
+  }
+
+  Ok(format!("
+    {inline}finch::input_iterator<{iterator}, {item}> {name}::begin() {{
+      return finch::input_iterator<{iterator}, {item}>({source});
+    }}
+
+    {inline}finch::input_iterator<{iterator}, {item}> {name}::end() {{
+      return finch::input_iterator<{iterator}, {item}>();
+    }}",
+    inline=ctx.inline(),
+    name=class.name,
+    item=item,
+    iterator=iterator,
+    source=source,
+  ))
+}
+
+fn to_exception_header(enum_: &FinchEnum) -> String {
+  let tag = if is_fieldless(enum_) {
+    enum_.name.clone()
+  } else {
+    format!("{}::Tag", enum_.name)
+  };
+
+  format!(r#"
+    class {name}Exception : public Exception {{
+    public:
+      {name}Exception({name} value, const std::string &message) : Exception(message), value(std::move(value)) {{}}
+
+      const {name} &get_value() const {{
+        return this->value;
+      }}
+
+      [[noreturn]] static void raise({name} value);
+
+    private:
+      {name} value;
+    }};
+
+    {subclasses}
+
+    inline void {name}Exception::raise({name} value) {{
+      #ifdef finch_bindgen_EXCEPTIONS
+        switch (value{get_tag}) {{{cases}
+        }}
       #endif
+      std::cout << "fatal: Result returned Err({name})" << std::endl;
+      abort();
+    }}"#,
+    name=enum_.name,
+    get_tag=if is_fieldless(enum_) { "" } else { ".get_tag()" },
+    subclasses=enum_.variants.iter().map(|x| {
+      format!(r#"
+        class {name}{variant}Exception : public {name}Exception {{
+        public:
+          explicit {name}{variant}Exception({name} value) : {name}Exception(std::move(value), "{name}::{variant}") {{}}
+        }};"#,
+        name=enum_.name,
+        variant=x.name,
+      )
+    }).collect::<Vec<String>>().join("\n"),
+    cases=enum_.variants.iter().map(|x| {
+      format!("
+          case {tag}::{variant}:
+            throw {name}{variant}Exception(std::move(value));",
+        tag=tag,
+        name=enum_.name,
+        variant=x.name,
+      )
+    }).collect::<Vec<String>>().join(""),
+  )
+}
 
-      namespace {1} {{\n",
-      includes,
-      name_underscore
-    ))?;
+/// The C++ standard the generated code targets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "u32")]
+pub enum CppStandard {
+  Cpp11,
+  Cpp14,
+  /// Adds `std::optional` and `std::string_view`.
+  Cpp17,
+  /// Adds `std::span` and makes ranges model `std::ranges::input_range`.
+  Cpp20,
+  /// Adds `std::expected`.
+  Cpp23,
+}
 
-    header_file.write(header_content.as_bytes())?;
-    impl_file.write(impl_content.as_bytes())?;
+impl TryFrom<u32> for CppStandard {
+  type Error = String;
 
-    header_file.write_fmt(format_args!("\n}}\n\n#include \"{}-impl.h\"", name_underscore))?;
-    impl_file.write(b"\n}")?;
-  
-    copy_third_party(&self)?;
-    generate_cmake(&self)?;
-
-    Command::new("clang-format")
-      .arg("--style=Google")
-      .arg("-i")
-      .arg(format!("{}-finch_bindgen.h", name_underscore))
-      .arg(header_name)
-      .arg(impl_name)
-      .status()?;
-  
-    Ok(())
+  fn try_from(year: u32) -> Result<Self, String> {
+    match year {
+      11 => Ok(CppStandard::Cpp11),
+      14 => Ok(CppStandard::Cpp14),
+      17 => Ok(CppStandard::Cpp17),
+      20 => Ok(CppStandard::Cpp20),
+      23 => Ok(CppStandard::Cpp23),
+      _ => Err(format!("unsupported C++ standard {}, expected one of 11, 14, 17, 20 or 23", year)),
+    }
   }
 }
 
-#[derive(Clone, Debug, Default)]
-pub struct Builder {
-  config: Config,
+impl CppStandard {
+  /// The number CMake's `CXX_STANDARD` and `-std=c++` take.
+  fn number(self) -> u32 {
+    match self {
+      CppStandard::Cpp11 => 11,
+      CppStandard::Cpp14 => 14,
+      CppStandard::Cpp17 => 17,
+      CppStandard::Cpp20 => 20,
+      CppStandard::Cpp23 => 23,
+    }
+  }
 }
 
-impl Builder {
-  pub fn new() -> Self {
-    std::default::Default::default()
+impl FromStr for CppStandard {
+  type Err = String;
+
+  /// Parses `17`, `c++17` or `C++17`.
+  fn from_str(s: &str) -> Result<Self, String> {
+    let year = s.strip_prefix("c++").or_else(|| s.strip_prefix("C++")).unwrap_or(s);
+    year.parse::<u32>()
+      .map_err(|_| format!("invalid C++ standard `{}`", s))
+      .and_then(CppStandard::try_from)
   }
+}
 
-  pub fn with_out_dir<T: Into<PathBuf>>(mut self, out_dir: T) -> Self {
-    self.config.out_dir = out_dir.into();
-    self
+/// How the generated headers are formatted once written.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Formatter {
+  /// Leave the generated code as it is.
+  None,
+  /// Run `clang-format` with the given `--style`, e.g. `Google` or `LLVM`.
+  ClangFormat(String),
+  /// Run `clang-format` with the style from a `.clang-format` file.
+  ClangFormatFile(PathBuf),
+  /// Re-indent the generated code by brace depth, without any external tool.
+  Indent,
+}
+
+impl Formatter {
+  /// Formats the contents of the file at `path`, which doesn't have to exist yet.
+  fn format(&self, path: &Path, code: String) -> Result<String, Error> {
+    match self {
+      Formatter::None => Ok(code),
+      Formatter::Indent => Ok(indent(&code)),
+      Formatter::ClangFormat(style) => clang_format(style, path, &code),
+      Formatter::ClangFormatFile(style_path) => clang_format(&format!("file:{}", style_path.display()), path, &code),
+    }
+  }
+}
+
+/// Runs `clang-format` over stdin, with `path` standing in for the file name so
+/// that `--style=file` finds the `.clang-format` next to the output.
+fn clang_format(style: &str, path: &Path, code: &str) -> Result<String, Error> {
+  let format_error = |message: String| Error::Format {
+    path: path.to_path_buf(),
+    message,
+  };
+
+  let mut child = Command::new("clang-format")
+    .arg(format!("--style={}", style))
+    .arg(format!("--assume-filename={}", path.display()))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|err| format_error(format!("could not run clang-format: {}", err)))?;
+
+  child.stdin.take().unwrap().write_all(code.as_bytes())?;
+  let output = child.wait_with_output()?;
+
+  if !output.status.success() {
+    return Err(format_error(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+  }
+
+  String::from_utf8(output.stdout).map_err(|err| format_error(err.to_string()))
+}
+
+fn is_access_label(line: &str) -> bool {
+  line == "public:" || line == "protected:" || line == "private:"
+}
+
+/// Re-indents C++ by brace depth. The snippets the output is assembled from keep
+/// the indentation of the Rust source they were written in, which says nothing
+/// about their nesting in the generated file.
+fn indent(code: &str) -> String {
+  let mut s = String::new();
+  let mut depth = 0usize;
+  let mut in_comment = false;
+  let mut pending_blank = false;
+  let mut after_open = true;
+
+  for line in code.lines().map(str::trim) {
+    if line.is_empty() {
+      pending_blank = true;
+      continue;
+    }
+
+    let continues_comment = in_comment;
+    let mut in_string = None;
+    let mut seen_code = false;
+    let mut leading_closes = 0;
+    let mut opens = 0;
+    let mut closes = 0;
+    // Inside a numeric literal, where `'` is a digit separator as in `1'000`.
+    let mut in_number = false;
+    let mut prev = ' ';
+
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+      let is_word = |c: char| c.is_alphanumeric() || c == '_';
+      if c.is_ascii_digit() && !is_word(prev) && prev != '\'' {
+        in_number = true;
+      } else if !(is_word(c) || c == '.' || c == '\'') {
+        in_number = false;
+      }
+      prev = c;
+
+      if in_comment {
+        if c == '*' && chars.peek() == Some(&'/') {
+          chars.next();
+          in_comment = false;
+        }
+        continue;
+      }
+
+      if let Some(quote) = in_string {
+        if c == '\\' {
+          chars.next();
+        } else if c == quote {
+          in_string = None;
+        }
+        continue;
+      }
+
+      match c {
+        '\'' if in_number => {},
+        '"' | '\'' => in_string = Some(c),
+        '/' if chars.peek() == Some(&'/') => break,
+        '/' if chars.peek() == Some(&'*') => {
+          chars.next();
+          in_comment = true;
+        },
+        '{' => opens += 1,
+        '}' => {
+          closes += 1;
+          if !seen_code {
+            leading_closes += 1;
+          }
+        },
+        _ => {},
+      }
+
+      if c != '}' {
+        seen_code = true;
+      }
+    }
+
+    // Blank lines are kept between declarations, not at the edges of a block.
+    if pending_blank && !after_open && leading_closes == 0 {
+      s.push('\n');
+    }
+    pending_blank = false;
+
+    let mut level = depth.saturating_sub(leading_closes);
+    if is_access_label(line) {
+      level = level.saturating_sub(1);
+    }
+
+    if !line.starts_with('#') || continues_comment {
+      s += &"  ".repeat(level);
+      if continues_comment && line.starts_with('*') {
+        s.push(' ');
+      }
+    }
+    s += line;
+    s.push('\n');
+
+    depth = (depth + opens).saturating_sub(closes);
+    after_open = opens > closes || is_access_label(line);
+  }
+
+  s
+}
+
+fn warn<T: fmt::Display>(message: T) {
+  // Build scripts only surface warnings printed in cargo's format.
+  if std::env::var_os("OUT_DIR").is_some() {
+    println!("cargo:warning={}", message);
+  } else {
+    eprintln!("warning: {}", message);
+  }
+}
+
+/// Checks `item` against a scratch copy of the context, so that a rejected item
+/// leaves no templates or includes behind.
+fn is_supported<T: ToCPP>(ctx: &Context, item: &T) -> bool {
+  let mut scratch = ctx.clone();
+  match item.to_header(&mut scratch).and_then(|_| item.to_impl(&mut scratch)) {
+    Ok(_) => true,
+    Err(err) => {
+      warn(format_args!("skipping {}", err));
+      false
+    },
+  }
+}
+
+/// Drops every enum and class member whose signature can't be converted.
+fn skip_unsupported(ctx: &mut Context, classes: &mut [FinchClass], enums: &mut Vec<FinchEnum>) {
+  // Dropping an enum makes the enums holding it unsupported in turn.
+  loop {
+    let unsupported = ctx.enums.values()
+      .filter(|x| !is_supported(ctx, *x))
+      .map(|x| x.c_name.clone())
+      .collect::<Vec<String>>();
+
+    if unsupported.is_empty() {
+      break;
+    }
+
+    for c_name in unsupported {
+      ctx.enums.remove(&c_name);
+    }
+  }
+
+  enums.retain(|x| ctx.enums.contains_key(&x.c_name));
+
+  for class in classes.iter_mut() {
+    if class.new.as_ref().is_some_and(|x| !is_supported(ctx, x)) {
+      class.new = None;
+    }
+
+    class.statics.retain(|x| is_supported(ctx, x));
+    class.methods.retain(|x| is_supported(ctx, x));
+    class.getters.retain(|x| is_supported(ctx, x));
+    class.setters.retain(|x| is_supported(ctx, x));
+  }
+}
+
+/// Vendored headers used by the generated code, keyed by file name.
+fn copy_third_party(ctx: &Context, config: &Config) -> BTreeMap<String, String> {
+  let mut files = BTreeMap::new();
+
+  if ctx.use_optional && ctx.cpp_standard < CppStandard::Cpp17 {
+    files.insert(config.third_party_name("optional"), include_str!("../third_party/optional.hpp").to_string());
+  }
+
+  if ctx.use_expected && ctx.cpp_standard < CppStandard::Cpp23 {
+    files.insert(config.third_party_name("expected"), include_str!("../third_party/expected.hpp").to_string());
+  }
+  
+  files
+}
+
+fn generate_cmake(config: &Config, name: &str) -> Result<Option<String>, Error> {
+  if !config.generate_cmake {
+    return Ok(None);
+  }
+
+  let mut cmake = format!(
+    "set(CRATE_NAME \"{}\")\nset({0}_INCLUDE_DIR \"${{CMAKE_CURRENT_SOURCE_DIR}}/{}\")\n\n{}",
+    name,
+    to_cmake_path(&config.include_dir),
+    include_str!("../CMakeLists.txt.in"),
+  );
+
+  // The out-of-line definitions are compiled once into a library linking the
+  // Rust one, which C++ targets link instead.
+  if config.separate_source {
+    cmake += &format!("
+add_library(${{CRATE_NAME}}-cpp STATIC ${{CMAKE_CURRENT_SOURCE_DIR}}/{})
+add_dependencies(${{CRATE_NAME}}-cpp ${{CRATE_NAME}})
+set_target_properties(${{CRATE_NAME}}-cpp PROPERTIES CXX_STANDARD {} CXX_STANDARD_REQUIRED ON)
+target_include_directories(${{CRATE_NAME}}-cpp PUBLIC ${{${{CRATE_NAME}}_INCLUDE_DIR}})
+target_link_libraries(${{CRATE_NAME}}-cpp PUBLIC ${{${{CRATE_NAME}}_SHARED_LIBS}})
+",
+      config.source_name(&name.replace("-", "_")),
+      config.cpp_standard.number(),
+    );
+  }
+
+  Ok(Some(cmake))
+}
+
+/// Joins the components of a relative path with `/`, as CMake and `#include`
+/// directives expect on every platform.
+fn to_cmake_path(path: &Path) -> String {
+  path.components().map(|x| x.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// Everything a generation run produces, formatted but not written anywhere.
+#[derive(Clone, Debug)]
+pub struct GeneratedBindings {
+  /// The crate name the file names are derived from, with `-` replaced by `_`.
+  pub crate_name: String,
+  /// `{crate}.h`, the header C++ code includes. With a single header it holds
+  /// all the other headers as well.
+  pub header: String,
+  /// `{crate}-impl.h`, the definitions included at the end of the header,
+  /// unless they go into `source` instead.
+  pub impl_header: Option<String>,
+  /// `{crate}.cpp`, the out-of-line definitions when a separate source file is
+  /// generated.
+  pub source: Option<String>,
+  /// `{crate}-finch_bindgen.h`, the raw FFI declarations with the template
+  /// instantiations the wrappers rely on, unless merged into `header`.
+  pub bindgen_header: Option<String>,
+  /// Vendored headers the generated code includes, keyed by file name.
+  pub third_party: BTreeMap<String, String>,
+  /// `CMakeLists.txt`, unless CMake generation is disabled.
+  pub cmake: Option<String>,
+}
+
+impl GeneratedBindings {
+  /// Merges every header into `header`, behind a single include guard.
+  fn amalgamate(mut self, config: &Config) -> Self {
+    let mut files = BTreeMap::new();
+    if let Some(bindgen_header) = self.bindgen_header.take() {
+      files.insert(config.include_name(&config.bindgen_name(&self.crate_name)), bindgen_header);
+    }
+    if let Some(impl_header) = self.impl_header.take() {
+      files.insert(config.include_name(&config.impl_name(&self.crate_name)), impl_header);
+    }
+    for (name, contents) in std::mem::take(&mut self.third_party) {
+      files.insert(config.include_name(&name), contents);
+    }
+
+    self.header = format!(
+      "#ifndef finch_bindgen_{0}_H\n#define finch_bindgen_{0}_H\n\n{1}\n#endif\n",
+      self.crate_name,
+      inline_includes(&self.header, &files, &mut HashSet::new()),
+    );
+    self
+  }
+}
+
+/// Replaces each `#include "..."` of one of `files` with its contents, the first
+/// time it is included, and drops `#pragma once` as the result has its own guard.
+fn inline_includes(code: &str, files: &BTreeMap<String, String>, included: &mut HashSet<String>) -> String {
+  let mut result = String::new();
+  for line in code.lines() {
+    let directive = line.trim();
+    if directive == "#pragma once" {
+      continue;
+    }
+
+    let name = directive.strip_prefix("#include \"").and_then(|x| x.split('"').next());
+    if let Some((name, contents)) = name.and_then(|x| files.get_key_value(x)) {
+      if included.insert(name.clone()) {
+        result += &inline_includes(contents, files, included);
+      }
+      continue;
+    }
+
+    result += line;
+    result += "\n";
+  }
+  result
+}
+
+/// Name of the file in `out_dir` listing every file the last run generated.
+const MANIFEST_NAME: &str = ".finch-cc-manifest";
+
+const MANIFEST_HEADER: &str = "# Files generated by finch-cc, relative to this directory, after the hash of their contents.\n";
+
+/// FNV-1a, which unlike `DefaultHasher` gives the same result on every
+/// platform and Rust release, so a manifest stays valid across toolchains.
+fn content_hash(bytes: &[u8]) -> String {
+  let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+  });
+  format!("{:016x}", hash)
+}
+
+/// The files `generate` wrote to `out_dir`, including the manifest itself.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+  /// Files that were created or whose contents changed.
+  pub written: Vec<PathBuf>,
+  /// Files that were already up to date and kept their mtime.
+  pub unchanged: Vec<PathBuf>,
+  /// Headers the previous manifest listed that are no longer generated and
+  /// hadn't been edited.
+  pub removed: Vec<PathBuf>,
+}
+
+/// Scratch directory for the frontend's raw bindgen header, so that the patched
+/// copy in `out_dir` is only touched when its contents change.
+struct StagingDir(PathBuf);
+
+impl StagingDir {
+  fn new() -> Result<Self, Error> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    // The name is unpredictable and the directory must not exist yet, so
+    // nobody else can plant files in it or point it somewhere else.
+    loop {
+      let mut hasher = RandomState::new().build_hasher();
+      hasher.write_u32(std::process::id());
+      hasher.write_usize(COUNT.fetch_add(1, Ordering::Relaxed));
+      let path = std::env::temp_dir().join(format!("finch-cc-{:016x}", hasher.finish()));
+
+      let mut builder = std::fs::DirBuilder::new();
+      #[cfg(unix)]
+      std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+      match builder.create(&path) {
+        Ok(()) => return Ok(Self(path)),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+        Err(e) => return Err(e.into()),
+      }
+    }
+  }
+}
+
+impl Drop for StagingDir {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.0);
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+  out_dir: PathBuf,
+  generate_cmake: bool,
+  expected_results: bool,
+  unique_ptr: bool,
+  skip_unsupported: bool,
+  formatter: Formatter,
+  cpp_standard: CppStandard,
+  separate_source: bool,
+  single_header: bool,
+  namespace: Option<String>,
+  module_namespaces: bool,
+  include_dir: PathBuf,
+  include_prefix: PathBuf,
+  header_extension: String,
+  header_name: String,
+  impl_name: String,
+  bindgen_name: String,
+  source_name: String,
+  check_build_files: bool,
+  /// The crate `Builder::config` found along with its settings, so generation
+  /// doesn't have to look it up again.
+  crate_name: Option<String>,
+  /// The root module of that crate, which `module_namespaces` starts from.
+  crate_root: Option<PathBuf>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      out_dir: std::env::current_dir().unwrap(),
+      generate_cmake: true,
+      expected_results: false,
+      unique_ptr: true,
+      skip_unsupported: false,
+      formatter: Formatter::Indent,
+      cpp_standard: CppStandard::Cpp11,
+      separate_source: false,
+      single_header: false,
+      namespace: None,
+      module_namespaces: false,
+      include_dir: PathBuf::from("include"),
+      include_prefix: PathBuf::new(),
+      header_extension: "h".to_string(),
+      header_name: "{crate}.{ext}".to_string(),
+      impl_name: "{crate}-impl.{ext}".to_string(),
+      bindgen_name: "{crate}-finch_bindgen.{ext}".to_string(),
+      source_name: "{crate}.cpp".to_string(),
+      check_build_files: false,
+      crate_name: None,
+      crate_root: None,
+    }
+  }
+}
+
+/// The crate bindings are generated for. Its name comes from the frontend, so
+/// it is the crate the bindings are generated from, and its manifest is the
+/// one Cargo finds from the working directory, checked to be for that crate.
+struct Package {
+  name: String,
+  manifest_path: PathBuf,
+  manifest: toml::Table,
+}
+
+impl Package {
+  fn locate() -> Result<Self, Error> {
+    let name = get_package_name()?;
+
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(cargo)
+      .args(["locate-project", "--message-format", "plain"])
+      .output()?;
+
+    if !output.status.success() {
+      return Err(Error::Config {
+        path: std::env::current_dir()?,
+        message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+      });
+    }
+
+    let manifest_path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let contents = std::fs::read_to_string(&manifest_path)?;
+    let manifest = toml::from_str::<toml::Table>(&contents).map_err(|err| Error::Config {
+      path: manifest_path.clone(),
+      message: err.to_string(),
+    })?;
+
+    // Settings from any other crate's manifest would silently not apply.
+    let manifest_name = manifest.get("package").and_then(|x| x.get("name")).and_then(|x| x.as_str());
+    if manifest_name != Some(name.as_str()) {
+      return Err(Error::Config {
+        path: manifest_path,
+        message: format!("expected the manifest of `{}`, which bindings are generated for", name),
+      });
+    }
+
+    Ok(Package { name, manifest_path, manifest })
+  }
+
+  fn dir(&self) -> &Path {
+    self.manifest_path.parent().unwrap_or_else(|| Path::new("."))
+  }
+
+  /// The root module of the crate's library, `src/lib.rs` unless the manifest
+  /// sets another path.
+  fn crate_root(&self) -> PathBuf {
+    let path = self.manifest.get("lib").and_then(|x| x.get("path")).and_then(|x| x.as_str());
+    self.dir().join(path.unwrap_or("src/lib.rs"))
+  }
+}
+
+/// A token of Rust source, as far as finding modules and the types declared in
+/// them needs. Comments and literals other than strings are dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RustToken {
+  Ident(String),
+  Str(String),
+  Punct(char),
+}
+
+fn rust_tokens(source: &str) -> Vec<RustToken> {
+  // The contents of a string starting at `i`, and the index after its closing
+  // quote and `hashes` `#`s. Escapes are skipped but not resolved.
+  fn string(chars: &[char], mut i: usize, raw: bool, hashes: usize) -> (String, usize) {
+    let start = i;
+    while i < chars.len() {
+      if chars[i] == '\\' && !raw {
+        i += 2;
+      } else if chars[i] == '"' && chars[i + 1..].iter().take(hashes).filter(|x| **x == '#').count() == hashes {
+        return (chars[start..i].iter().collect(), i + 1 + hashes);
+      } else {
+        i += 1;
+      }
+    }
+    (chars[start.min(chars.len())..].iter().collect(), chars.len())
+  }
+
+  let chars = source.chars().collect::<Vec<char>>();
+  let is_ident = |x: char| x.is_alphanumeric() || x == '_';
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    let next = chars.get(i + 1).copied();
+    if c.is_whitespace() {
+      i += 1;
+    } else if c == '/' && next == Some('/') {
+      while i < chars.len() && chars[i] != '\n' {
+        i += 1;
+      }
+    } else if c == '/' && next == Some('*') {
+      // Block comments nest.
+      let mut depth = 0;
+      while i < chars.len() {
+        if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+          depth += 1;
+          i += 2;
+        } else if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+          depth -= 1;
+          i += 2;
+          if depth == 0 {
+            break;
+          }
+        } else {
+          i += 1;
+        }
+      }
+    } else if c == '"' {
+      let (contents, end) = string(&chars, i + 1, false, 0);
+      tokens.push(RustToken::Str(contents));
+      i = end;
+    } else if c == '\'' {
+      // A character literal, or else a lifetime or label whose name is read as
+      // an identifier next.
+      if next == Some('\\') {
+        i += 3;
+        while i < chars.len() && chars[i] != '\'' {
+          i += 1;
+        }
+        i += 1;
+      } else if chars.get(i + 2) == Some(&'\'') {
+        i += 3;
+      } else {
+        i += 1;
+      }
+    } else if is_ident(c) {
+      let start = i;
+      while i < chars.len() && is_ident(chars[i]) {
+        i += 1;
+      }
+      let word = chars[start..i].iter().collect::<String>();
+      let hashes = chars[i..].iter().take_while(|x| **x == '#').count();
+
+      match (word.as_str(), chars.get(i).copied()) {
+        ("b" | "c", Some('"')) => {
+          let (contents, end) = string(&chars, i + 1, false, 0);
+          tokens.push(RustToken::Str(contents));
+          i = end;
+        },
+        ("r" | "br" | "cr", Some('"' | '#')) if chars.get(i + hashes) == Some(&'"') => {
+          let (contents, end) = string(&chars, i + hashes + 1, true, hashes);
+          tokens.push(RustToken::Str(contents));
+          i = end;
+        },
+        ("b", Some('\'')) => {},
+        // A raw identifier, `r#name`, which is read next.
+        ("r", Some('#')) if hashes == 1 => i += 1,
+        _ => tokens.push(RustToken::Ident(word)),
+      }
+    } else {
+      tokens.push(RustToken::Punct(c));
+      i += 1;
+    }
+  }
+  tokens
+}
+
+/// Finds the Rust module each struct and enum of the crate is declared in, by
+/// following the `mod` items from the crate root. The frontend only reports
+/// names, so a name declared in more than one module is left out with a
+/// warning, as are the crate root's own types.
+fn module_paths(crate_root: &Path) -> Result<BTreeMap<String, String>, Error> {
+  let mut declared = BTreeMap::<String, BTreeSet<String>>::new();
+  let dir = crate_root.parent().unwrap_or_else(|| Path::new("."));
+  scan_module_file(crate_root, &[], dir, &mut declared)?;
+
+  let mut modules = BTreeMap::new();
+  for (name, paths) in declared {
+    if paths.len() > 1 {
+      warn(format_args!("`{}` is declared in more than one module, so it stays in the crate's namespace", name));
+    } else if let Some(path) = paths.into_iter().find(|x| !x.is_empty()) {
+      modules.insert(name, path);
+    }
+  }
+  Ok(modules)
+}
+
+/// Records the types declared in the module at `path` and the modules nested in
+/// it, whose files are looked up in `dir`.
+fn scan_module_file(path: &Path, module: &[String], dir: &Path, declared: &mut BTreeMap<String, BTreeSet<String>>) -> Result<(), Error> {
+  use RustToken::*;
+
+  let tokens = rust_tokens(&std::fs::read_to_string(path)?);
+  let file_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+  // The file's own module and the inline modules open in it, with the depth of
+  // delimiters their items are at and the directory their file modules are in.
+  let mut scopes = vec![(module.to_vec(), 0, dir.to_path_buf())];
+  let mut depth: usize = 0;
+  // The value of a `#[path = "..."]` attribute on the next item.
+  let mut path_attribute: Option<String> = None;
+
+  let mut i = 0;
+  while i < tokens.len() {
+    let (module, item_depth, dir) = scopes.last().cloned().unwrap();
+    let at = |offset: usize| tokens.get(i + offset);
+
+    match (&tokens[i], depth == item_depth) {
+      (Punct('#'), true) => {
+        if let (Some(Punct('[')), Some(Ident(name)), Some(Punct('=')), Some(Str(value))) = (at(1), at(2), at(3), at(4)) {
+          if name == "path" {
+            path_attribute = Some(value.clone());
+          }
+        }
+      },
+      (Ident(keyword), true) if keyword == "struct" || keyword == "enum" => {
+        if let Some(Ident(name)) = at(1) {
+          declared.entry(name.clone()).or_default().insert(module.join("::"));
+        }
+      },
+      (Ident(keyword), true) if keyword == "mod" => {
+        if let Some(Ident(name)) = at(1) {
+          let mut nested = module.clone();
+          nested.push(name.clone());
+
+          match at(2) {
+            Some(Punct('{')) => {
+              depth += 1;
+              scopes.push((nested, depth, dir.join(name)));
+              path_attribute = None;
+              i += 3;
+              continue;
+            },
+            Some(Punct(';')) => {
+              // A file named by `#[path]` is relative to the file declaring it
+              // outside of inline modules, and keeps its nested modules next
+              // to it like a `mod.rs`.
+              let candidates = match path_attribute.take() {
+                Some(value) => {
+                  let file = if scopes.len() == 1 { file_dir.join(value) } else { dir.join(value) };
+                  let file_dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+                  vec![(file, file_dir)]
+                },
+                None => vec![
+                  (dir.join(format!("{}.rs", name)), dir.join(name)),
+                  (dir.join(name).join("mod.rs"), dir.join(name)),
+                ],
+              };
+
+              match candidates.into_iter().find(|(file, _)| file.is_file()) {
+                Some((file, nested_dir)) => scan_module_file(&file, &nested, &nested_dir, declared)?,
+                None => warn(format_args!("can't find the file of module `{}`, its types stay in the crate's namespace", nested.join("::"))),
+              }
+            },
+            _ => {},
+          }
+        }
+      },
+      (Punct(';'), true) => path_attribute = None,
+      (Punct('(' | '[' | '{'), _) => {
+        if depth == item_depth && tokens[i] == Punct('{') {
+          path_attribute = None;
+        }
+        depth += 1;
+      },
+      (Punct(')' | ']' | '}'), _) => {
+        if depth == item_depth && scopes.len() > 1 {
+          scopes.pop();
+        }
+        depth = depth.saturating_sub(1);
+      },
+      _ => {},
+    }
+    i += 1;
+  }
+  Ok(())
+}
+
+/// Settings that can come from a configuration file or from `Builder` calls.
+/// Every one of them is optional so that the two can be layered.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Options {
+  out_dir: Option<PathBuf>,
+  generate_cmake: Option<bool>,
+  expected_results: Option<bool>,
+  unique_ptr: Option<bool>,
+  skip_unsupported: Option<bool>,
+  formatter: Option<Formatter>,
+  cpp_standard: Option<CppStandard>,
+  separate_source: Option<bool>,
+  single_header: Option<bool>,
+  namespace: Option<String>,
+  module_namespaces: Option<bool>,
+  include_dir: Option<PathBuf>,
+  include_prefix: Option<PathBuf>,
+  header_extension: Option<String>,
+  header_name: Option<String>,
+  impl_name: Option<String>,
+  bindgen_name: Option<String>,
+  source_name: Option<String>,
+  check_build_files: Option<bool>,
+}
+
+impl Options {
+  /// Reads `finch-cc.toml` next to the crate's `Cargo.toml`, or the
+  /// `[package.metadata.finch-cc]` table of the manifest if there is none.
+  /// Relative paths are resolved against the crate directory.
+  fn load(package: &Package) -> Result<Self, Error> {
+    let dir = package.dir();
+    let config_error = |path: PathBuf, err: toml::de::Error| Error::Config {
+      path,
+      message: err.to_string(),
+    };
+
+    let path = dir.join("finch-cc.toml");
+    let mut options = if path.exists() {
+      let contents = std::fs::read_to_string(&path)?;
+      toml::from_str::<Options>(&contents).map_err(|err| config_error(path, err))?
+    } else {
+      match package.manifest.get("package").and_then(|x| x.get("metadata")).and_then(|x| x.get("finch-cc")) {
+        Some(table) => table.clone().try_into::<Options>().map_err(|err| config_error(package.manifest_path.clone(), err))?,
+        None => Options::default(),
+      }
+    };
+
+    options.out_dir = options.out_dir.map(|x| dir.join(x));
+    if let Some(Formatter::ClangFormatFile(style_path)) = &options.formatter {
+      options.formatter = Some(Formatter::ClangFormatFile(dir.join(style_path)));
+    }
+
+    Ok(options)
+  }
+
+  /// Fills in everything unset in `self` from `fallback`.
+  fn or(self, fallback: Options) -> Options {
+    Options {
+      out_dir: self.out_dir.or(fallback.out_dir),
+      generate_cmake: self.generate_cmake.or(fallback.generate_cmake),
+      expected_results: self.expected_results.or(fallback.expected_results),
+      unique_ptr: self.unique_ptr.or(fallback.unique_ptr),
+      skip_unsupported: self.skip_unsupported.or(fallback.skip_unsupported),
+      formatter: self.formatter.or(fallback.formatter),
+      cpp_standard: self.cpp_standard.or(fallback.cpp_standard),
+      separate_source: self.separate_source.or(fallback.separate_source),
+      single_header: self.single_header.or(fallback.single_header),
+      namespace: self.namespace.or(fallback.namespace),
+      module_namespaces: self.module_namespaces.or(fallback.module_namespaces),
+      include_dir: self.include_dir.or(fallback.include_dir),
+      include_prefix: self.include_prefix.or(fallback.include_prefix),
+      header_extension: self.header_extension.or(fallback.header_extension),
+      header_name: self.header_name.or(fallback.header_name),
+      impl_name: self.impl_name.or(fallback.impl_name),
+      bindgen_name: self.bindgen_name.or(fallback.bindgen_name),
+      source_name: self.source_name.or(fallback.source_name),
+      check_build_files: self.check_build_files.or(fallback.check_build_files),
+    }
+  }
+
+  fn into_config(self) -> Config {
+    let default = Config::default();
+
+    Config {
+      out_dir: self.out_dir.unwrap_or(default.out_dir),
+      generate_cmake: self.generate_cmake.unwrap_or(default.generate_cmake),
+      expected_results: self.expected_results.unwrap_or(default.expected_results),
+      unique_ptr: self.unique_ptr.unwrap_or(default.unique_ptr),
+      skip_unsupported: self.skip_unsupported.unwrap_or(default.skip_unsupported),
+      formatter: self.formatter.unwrap_or(default.formatter),
+      cpp_standard: self.cpp_standard.unwrap_or(default.cpp_standard),
+      separate_source: self.separate_source.unwrap_or(default.separate_source),
+      single_header: self.single_header.unwrap_or(default.single_header),
+      namespace: self.namespace.or(default.namespace),
+      module_namespaces: self.module_namespaces.unwrap_or(default.module_namespaces),
+      include_dir: self.include_dir.unwrap_or(default.include_dir),
+      include_prefix: self.include_prefix.unwrap_or(default.include_prefix),
+      header_extension: self.header_extension.unwrap_or(default.header_extension),
+      header_name: self.header_name.unwrap_or(default.header_name),
+      impl_name: self.impl_name.unwrap_or(default.impl_name),
+      bindgen_name: self.bindgen_name.unwrap_or(default.bindgen_name),
+      source_name: self.source_name.unwrap_or(default.source_name),
+      check_build_files: self.check_build_files.unwrap_or(default.check_build_files),
+      crate_name: None,
+      crate_root: None,
+    }
+  }
+}
+
+impl Config {
+  fn to_frontend_cfg(&self, staging: &StagingDir) -> finch_frontend_api::Config {
+    finch_frontend_api::Config {
+      out_dir: Some(staging.0.clone()),
+    }
+  }
+
+  /// Expands the `{crate}` and `{ext}` placeholders of a file name template.
+  fn file_name(&self, template: &str, crate_name: &str) -> String {
+    template.replace("{crate}", crate_name).replace("{ext}", &self.header_extension)
+  }
+
+  fn header_name(&self, crate_name: &str) -> String {
+    self.file_name(&self.header_name, crate_name)
+  }
+
+  fn impl_name(&self, crate_name: &str) -> String {
+    self.file_name(&self.impl_name, crate_name)
+  }
+
+  fn bindgen_name(&self, crate_name: &str) -> String {
+    self.file_name(&self.bindgen_name, crate_name)
+  }
+
+  fn source_name(&self, crate_name: &str) -> String {
+    self.file_name(&self.source_name, crate_name)
+  }
+
+  fn third_party_name(&self, name: &str) -> String {
+    format!("{}.{}", name, self.header_extension)
+  }
+
+  /// What generated code puts in `#include "..."` for one of the headers,
+  /// relative to the include directory.
+  fn include_name(&self, file_name: &str) -> String {
+    to_cmake_path(&self.include_prefix.join(file_name))
+  }
+
+  fn include_path(&self, file_name: &str) -> PathBuf {
+    self.out_dir.join(&self.include_dir).join(&self.include_prefix).join(file_name)
+  }
+
+  fn header_path(&self, crate_name: &str) -> PathBuf {
+    self.include_path(&self.header_name(crate_name))
+  }
+
+  fn impl_path(&self, crate_name: &str) -> PathBuf {
+    self.include_path(&self.impl_name(crate_name))
+  }
+
+  fn source_path(&self, crate_name: &str) -> PathBuf {
+    self.out_dir.join(self.source_name(crate_name))
+  }
+
+  fn bindgen_path(&self, crate_name: &str) -> PathBuf {
+    self.include_path(&self.bindgen_name(crate_name))
+  }
+
+  /// Where each of the generated files goes, with its contents, followed by
+  /// the manifest listing them.
+  fn output_files(&self, bindings: &GeneratedBindings) -> Vec<(PathBuf, String)> {
+    let mut files = vec![
+      (self.header_path(&bindings.crate_name), bindings.header.clone()),
+    ];
+
+    if let Some(bindgen_header) = &bindings.bindgen_header {
+      files.push((self.bindgen_path(&bindings.crate_name), bindgen_header.clone()));
+    }
+
+    if let Some(impl_header) = &bindings.impl_header {
+      files.push((self.impl_path(&bindings.crate_name), impl_header.clone()));
+    }
+
+    if let Some(source) = &bindings.source {
+      files.push((self.source_path(&bindings.crate_name), source.clone()));
+    }
+
+    for (name, contents) in &bindings.third_party {
+      files.push((self.include_path(name), contents.clone()));
+    }
+
+    if let Some(cmake) = &bindings.cmake {
+      files.push((self.out_dir.join("CMakeLists.txt"), cmake.clone()));
+    }
+
+    self.with_manifest(files)
+  }
+
+  /// Appends the manifest listing `files`.
+  fn with_manifest(&self, mut files: Vec<(PathBuf, String)>) -> Vec<(PathBuf, String)> {
+    let mut manifest = MANIFEST_HEADER.to_string();
+    for (path, contents) in &files {
+      manifest += &format!(
+        "{}  {}\n",
+        content_hash(contents.as_bytes()),
+        to_cmake_path(path.strip_prefix(&self.out_dir).unwrap_or(path)),
+      );
+    }
+    files.push((self.manifest_path(), manifest));
+
+    files
+  }
+
+  fn manifest_path(&self) -> PathBuf {
+    self.out_dir.join(MANIFEST_NAME)
+  }
+
+  /// Headers the manifest already in `out_dir` lists that aren't among
+  /// `files`, left over from a run with a different layout or set of options.
+  /// Only files in the include directory that still hold what finch-cc wrote
+  /// are returned, anything edited since is kept with a warning.
+  fn stale_files(&self, files: &[(PathBuf, String)]) -> Vec<PathBuf> {
+    let previous = std::fs::read_to_string(self.manifest_path()).unwrap_or_default();
+    let current: HashSet<&Path> = files.iter().map(|(path, _)| path.as_path()).collect();
+    let include_dir = self.out_dir.join(&self.include_dir);
+
+    let mut stale = Vec::new();
+    for line in previous.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+      // Entries without a hash can't tell whether the file was edited since.
+      let (hash, name) = match line.split_once("  ") {
+        Some(entry) => entry,
+        None => continue,
+      };
+
+      let name = Path::new(name);
+      if !name.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        continue;
+      }
+
+      let path = self.out_dir.join(name);
+      if current.contains(path.as_path()) || !path.starts_with(&include_dir) {
+        continue;
+      }
+
+      match std::fs::read(&path) {
+        Ok(contents) if content_hash(&contents) == hash => stale.push(path),
+        Ok(_) => warn(format_args!("keeping {}, which is no longer generated but was edited since", path.display())),
+        Err(_) => {},
+      }
+    }
+    stale
+  }
+
+  /// Runs the whole generation and formats the result. Only the frontend's
+  /// scratch directory is written to.
+  pub fn generate_in_memory(&self) -> Result<GeneratedBindings, Error> {
+    let name = match &self.crate_name {
+      Some(name) => name.clone(),
+      None => get_package_name()?,
+    };
+    let name_underscore = name.replace("-", "_");
+
+    let mut ctx = Context::new(name_underscore.clone(), self)?;
+    let ctx = &mut ctx;
+  
+    let staging = StagingDir::new()?;
+    let output = self.to_frontend_cfg(&staging).generate()?;
+
+    // The frontend hands out hash maps, sort them so every run emits the same code.
+    let mut classes = output.classes.into_iter().map(|x| x.1).collect::<Vec<FinchClass>>();
+    classes.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut enums = output.enums.into_iter().map(|x| x.1).collect::<Vec<FinchEnum>>();
+    enums.sort_by(|a, b| a.name.cmp(&b.name));
+
+    ctx.enums = enums.iter().map(|x| (x.c_name.clone(), x.clone())).collect();
+    if self.module_namespaces {
+      let crate_root = match &self.crate_root {
+        Some(path) => path.clone(),
+        None => Package::locate()?.crate_root(),
+      };
+      ctx.mirror_modules(module_paths(&crate_root)?)?;
+    }
+
+    if self.skip_unsupported {
+      skip_unsupported(ctx, &mut classes, &mut enums);
+    }
+
+    // Enums hold each other by value, so each has to follow the ones it holds.
+    let enums = sort_enums(ctx, enums);
+
+    ctx.iterators = classes.iter().filter_map(|x| iterator_item(ctx, x).map(|item| (ctx.qualified(&x.name), item.clone()))).collect();
+  
+    let mut header_content = String::new();
+    let mut impl_content = String::new();
+    for class in &classes {
+      header_content += &ctx.in_module(&class.name, format!("class {};\n", class.name));
+    }
+
+    for enum_ in &enums {
+      let (header, impl_) = (enum_.to_header(ctx)?, enum_.to_impl(ctx)?);
+      header_content += &ctx.in_module(&enum_.name, format!("{}\n", header));
+      impl_content += &ctx.in_module(&enum_.name, format!("{}\n", impl_));
+    }
+
+    let mut class_content = String::new();
+    let mut footer_content = String::new();
+    for class in &classes {
+      let (header, impl_) = (class.to_header(ctx)?, class.to_impl(ctx)?);
+      class_content += &ctx.in_module(&class.name, format!("{}\n", header));
+      footer_content += &format!("{}\n", class.to_std_hash(ctx));
+      impl_content += &ctx.in_module(&class.name, format!("{}\n", impl_));
+    }
+
+    // Error types are only known once every class has been converted.
+    header_content += EXCEPTION_DEFINITION;
+    for enum_ in &enums {
+      if ctx.errors.contains(&enum_.c_name) {
+        header_content += &ctx.in_module(&enum_.name, format!("{}\n", to_exception_header(enum_)));
+      }
+    }
+
+    header_content += &class_content;
+
+    let bindgen_content = std::fs::read_to_string(staging.0.join(format!("{}-finch_bindgen.h", name_underscore)))?;
+
+    let bindgen_content = bindgen_content.replace(
+      "extern \"C\" {",
+      &(ctx.templates.clone().into_iter().collect::<Vec<String>>().join("\n") + "\n\nextern \"C\" {"),
+    );
+
+    let mut includes = "
+      #include <cstdarg>
+      #include <cstdint>
+      #include <cstdlib>
+      #include <cassert>
+      #include <functional>
+      #include <new>
+      #include <iostream>
+      #include <memory>
+      #include <stdexcept>
+      #include <string>
+      #include <type_traits>
+      #include <utility>\n".to_string();
+
+    if ctx.cpp_standard >= CppStandard::Cpp17 {
+      includes += "#include <string_view>\n";
+    }
+
+    if ctx.use_optional {
+      if ctx.cpp_standard >= CppStandard::Cpp17 {
+        includes += "#include <optional>\n";
+      } else {
+        includes += &format!("#include \"{}\"\n", self.include_name(&self.third_party_name("optional")));
+      }
+    }
+
+    if ctx.use_vector {
+      includes += "#include <vector>\n";
+    }
+
+    if ctx.use_variant {
+      includes += "#include <variant>\n";
+    }
+
+    if ctx.use_slice {
+      if ctx.cpp_standard >= CppStandard::Cpp20 {
+        includes += "#include <span>\n";
+      } else {
+        includes += SLICE_DEFINITION;
+      }
+    }
+
+    if ctx.use_iterator {
+      includes += "#include <cstddef>\n";
+      includes += "#include <iterator>\n";
+      if ctx.cpp_standard >= CppStandard::Cpp20 {
+        includes += "#include <ranges>\n";
+      }
+      includes += &ITERATOR_DEFINITION.replace("nonstd::optional", ctx.optional());
+    }
+
+    if ctx.use_expected {
+      if ctx.cpp_standard >= CppStandard::Cpp23 {
+        includes += "#include <expected>\n";
+        includes += STD_EXPECTED_DEFINITION;
+      } else {
+        includes += &format!("#include \"{}\"\n", self.include_name(&self.third_party_name("expected")));
+        includes += EXPECTED_DEFINITION;
+      }
+    }
+
+    // With a separate source file the definitions are compiled on their own
+    // instead of being pulled into every file including the header, and are
+    // the only ones that aren't `inline`.
+    let impl_include = if self.separate_source {
+      "".to_string()
+    } else {
+      format!("#include \"{}\"", self.include_name(&self.impl_name(&name_underscore)))
+    };
+
+    let (namespace_open, namespace_close) = ctx.namespace_block(&ctx.namespace);
+
+    let header = format!("
+      #pragma once
+      
+      {}
+  
+      #include \"{}\"
+
+      #if defined(__cpp_exceptions) || defined(__EXCEPTIONS) || defined(_CPPUNWIND)
+        #define finch_bindgen_EXCEPTIONS
+      #endif
+  
+      {}
+      {}
+      {}
+
+      {}
+      {}\n",
+      includes,
+      self.include_name(&self.bindgen_name(&name_underscore)),
+      namespace_open,
+      header_content,
+      namespace_close,
+      footer_content,
+      impl_include,
+    );
+
+    let (impl_, source) = if self.separate_source {
+      let source = format!("
+        #include \"{}\"
+
+        {}
+        {}
+        {}\n",
+        self.include_name(&self.header_name(&name_underscore)),
+        namespace_open,
+        impl_content,
+        namespace_close,
+      );
+
+      (None, Some(self.formatter.format(&self.source_path(&name_underscore), source)?))
+    } else {
+      let impl_ = format!("
+        #pragma once
+        
+        {}
+    
+        #if defined(__cpp_exceptions) || defined(__EXCEPTIONS) || defined(_CPPUNWIND)
+          #define finch_bindgen_EXCEPTIONS
+        #endif
+
+        {}
+        {}
+        {}\n",
+        includes,
+        namespace_open,
+        impl_content,
+        namespace_close,
+      );
+
+      (Some(self.formatter.format(&self.impl_path(&name_underscore), impl_)?), None)
+    };
+
+    // Vendored and build files are used as they are, only the generated C++ is
+    // formatted.
+    let bindings = GeneratedBindings {
+      bindgen_header: Some(self.formatter.format(&self.bindgen_path(&name_underscore), bindgen_content)?),
+      header: self.formatter.format(&self.header_path(&name_underscore), header)?,
+      impl_header: impl_,
+      source,
+      third_party: copy_third_party(ctx, self),
+      cmake: generate_cmake(self, &name)?,
+      crate_name: name_underscore,
+    };
+
+    if self.single_header {
+      Ok(bindings.amalgamate(self))
+    } else {
+      Ok(bindings)
+    }
+  }
+
+  /// Writes the generated files to `out_dir`, along with a manifest listing
+  /// them, removes the headers the previous manifest listed that are no longer
+  /// generated, unless they were edited, and returns which files were written,
+  /// unchanged or removed.
+  pub fn generate(self) -> Result<Manifest, Error> {
+    let bindings = self.generate_in_memory()?;
+    self.write_files(self.output_files(&bindings))
+  }
+
+  fn write_files(&self, files: Vec<(PathBuf, String)>) -> Result<Manifest, Error> {
+    // Everything is generated and formatted before anything is written, so an
+    // error leaves the previous output in place.
+    let mut manifest = Manifest {
+      removed: self.stale_files(&files),
+      ..Manifest::default()
+    };
+    for (path, contents) in files {
+      // Unchanged files keep their mtime, so the C++ including them isn't rebuilt.
+      if std::fs::read(&path).ok().as_deref() != Some(contents.as_bytes()) {
+        if let Some(dir) = path.parent() {
+          std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, contents)?;
+        manifest.written.push(path);
+      } else {
+        manifest.unchanged.push(path);
+      }
+    }
+
+    for path in &manifest.removed {
+      std::fs::remove_file(path)?;
+      // Drop directories the old layout leaves empty, stopping at the first
+      // one that still has something in it.
+      for dir in path.ancestors().skip(1).take_while(|dir| *dir != self.out_dir) {
+        if std::fs::remove_dir(dir).is_err() {
+          break;
+        }
+      }
+    }
+
+    Ok(manifest)
+  }
+
+  /// Compares the generated headers with the ones in the include directory,
+  /// and the build files outside of it if `check_build_files` is set. The
+  /// manifest only records what the last run wrote and is never compared.
+  pub fn check(self) -> Result<(), Error> {
+    let bindings = self.generate_in_memory()?;
+    self.check_files(self.output_files(&bindings))
+  }
+
+  fn check_files(&self, outputs: Vec<(PathBuf, String)>) -> Result<(), Error> {
+    let mut files = Vec::new();
+    let mut diff = String::new();
+
+    // Files left over from a previous layout are stale even when empty, and
+    // show up in the diff as deletions.
+    let removed: Vec<_> = self.stale_files(&outputs).into_iter().map(|path| (path, None)).collect();
+    let outputs = outputs.into_iter().map(|(path, contents)| (path, Some(contents)));
+
+    let include_dir = self.out_dir.join(&self.include_dir);
+    let manifest_path = self.manifest_path();
+    let compared = outputs.chain(removed)
+      .filter(|(path, _)| *path != manifest_path)
+      .filter(|(path, _)| self.check_build_files || path.starts_with(&include_dir));
+    for (path, contents) in compared {
+      let current = std::fs::read_to_string(&path).unwrap_or_default();
+      if contents.as_ref() != Some(&current) {
+        let contents = contents.unwrap_or_default();
+        let name = path.display().to_string();
+        diff += &TextDiff::from_lines(current.as_str(), contents.as_str())
+          .unified_diff()
+          .header(&name, &name)
+          .to_string();
+        files.push(path);
+      }
+    }
+
+    if files.is_empty() {
+      Ok(())
+    } else {
+      Err(Error::Stale { files, diff })
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+  options: Options,
+}
+
+impl Builder {
+  pub fn new() -> Self {
+    std::default::Default::default()
+  }
+
+  pub fn with_out_dir<T: Into<PathBuf>>(mut self, out_dir: T) -> Self {
+    self.options.out_dir = Some(out_dir.into());
+    self
   }
 
   pub fn with_generate_cmake(mut self, value: bool) -> Self {
-    self.config.generate_cmake = value;
+    self.options.generate_cmake = Some(value);
+    self
+  }
+
+  /// Return `expected<T, E>` from methods that return a `Result` instead of
+  /// throwing, for consumers compiled without exceptions.
+  pub fn with_expected_results(mut self, value: bool) -> Self {
+    self.options.expected_results = Some(value);
+    self
+  }
+
+  /// Return owned class instances as `std::unique_ptr` (the default) rather
+  /// than raw pointers the caller has to `delete`.
+  pub fn with_unique_ptr(mut self, value: bool) -> Self {
+    self.options.unique_ptr = Some(value);
+    self
+  }
+
+  /// Leave out methods, fields and enums using types that can't be converted,
+  /// with a warning, instead of failing with `Error::UnsupportedType`.
+  pub fn with_skip_unsupported(mut self, value: bool) -> Self {
+    self.options.skip_unsupported = Some(value);
+    self
+  }
+
+  /// Defaults to `Formatter::Indent`, which needs no external tools.
+  pub fn with_formatter(mut self, formatter: Formatter) -> Self {
+    self.options.formatter = Some(formatter);
+    self
+  }
+
+  /// The C++ standard the generated code is compiled with, C++11 by default.
+  /// Newer standards use `std::optional`, `std::string_view`, `std::span` and
+  /// `std::expected` where available instead of the vendored replacements.
+  pub fn with_cpp_standard(mut self, standard: CppStandard) -> Self {
+    self.options.cpp_standard = Some(standard);
+    self
+  }
+
+  /// Write the definitions to `{crate}.cpp` next to `CMakeLists.txt`, built
+  /// into a `{crate}-cpp` library, so the header only declares them and isn't
+  /// recompiled into every translation unit.
+  pub fn with_separate_source(mut self, value: bool) -> Self {
+    self.options.separate_source = Some(value);
+    self
+  }
+
+  /// Write a single self-contained `{crate}.h` holding the bindgen
+  /// declarations, the vendored headers and the definitions, for vendoring it
+  /// into a C++ project as it is.
+  pub fn with_single_header(mut self, value: bool) -> Self {
+    self.options.single_header = Some(value);
+    self
+  }
+
+  /// The namespace to declare the classes in, e.g. `acme::geometry`, instead of
+  /// the crate name. The raw FFI stays in `finch::bindgen::{crate}`.
+  pub fn with_namespace<S: Into<String>>(mut self, namespace: S) -> Self {
+    self.options.namespace = Some(namespace.into());
+    self
+  }
+
+  /// Declares each class and enum in a namespace nested in the public one
+  /// after the Rust module it is declared in, e.g. `acme::geometry::shapes`
+  /// for `crate::shapes::Circle`. Off by default.
+  pub fn with_module_namespaces(mut self, module_namespaces: bool) -> Self {
+    self.options.module_namespaces = Some(module_namespaces);
+    self
+  }
+
+  /// The directory under `out_dir` that is added to the include path, `include`
+  /// by default.
+  pub fn with_include_dir<T: Into<PathBuf>>(mut self, include_dir: T) -> Self {
+    self.options.include_dir = Some(include_dir.into());
+    self
+  }
+
+  /// A directory under the include directory to put the headers in, e.g.
+  /// `acme/geometry`, which then prefixes every generated `#include`.
+  pub fn with_include_prefix<T: Into<PathBuf>>(mut self, include_prefix: T) -> Self {
+    self.options.include_prefix = Some(include_prefix.into());
+    self
+  }
+
+  /// The extension of the headers, `h` by default. It replaces `{ext}` in the
+  /// file names and is used for the vendored headers too.
+  pub fn with_header_extension<S: Into<String>>(mut self, extension: S) -> Self {
+    self.options.header_extension = Some(extension.into());
+    self
+  }
+
+  /// The name of the main header, `{crate}.{ext}` by default. `{crate}` is the
+  /// crate name with `-` replaced by `_`.
+  pub fn with_header_name<S: Into<String>>(mut self, template: S) -> Self {
+    self.options.header_name = Some(template.into());
+    self
+  }
+
+  /// The name of the header with the definitions, `{crate}-impl.{ext}` by default.
+  pub fn with_impl_name<S: Into<String>>(mut self, template: S) -> Self {
+    self.options.impl_name = Some(template.into());
+    self
+  }
+
+  /// The name of the raw FFI header, `{crate}-finch_bindgen.{ext}` by default.
+  pub fn with_bindgen_name<S: Into<String>>(mut self, template: S) -> Self {
+    self.options.bindgen_name = Some(template.into());
+    self
+  }
+
+  /// The path of the separate source file relative to `out_dir`, `{crate}.cpp`
+  /// by default.
+  pub fn with_source_name<S: Into<String>>(mut self, template: S) -> Self {
+    self.options.source_name = Some(template.into());
+    self
+  }
+
+  /// Have `check` also compare `CMakeLists.txt` and the separate source file,
+  /// which are written outside the include directory. Off by default, as
+  /// repositories checking in the generated headers usually keep their own
+  /// build files.
+  pub fn with_check_build_files(mut self, value: bool) -> Self {
+    self.options.check_build_files = Some(value);
     self
   }
 
-  pub fn generate(self) -> Result<(), Box<dyn Error>> {
-    self.config.generate()
+  /// The final configuration: settings made on the builder take precedence
+  /// over the crate's configuration file, which takes precedence over the
+  /// defaults.
+  pub fn config(self) -> Result<Config, Error> {
+    let package = Package::locate()?;
+    let mut config = self.options.or(Options::load(&package)?).into_config();
+    config.crate_root = Some(package.crate_root());
+    config.crate_name = Some(package.name);
+    Ok(config)
+  }
+
+  pub fn generate(self) -> Result<Manifest, Error> {
+    self.config()?.generate()
+  }
+
+  /// Returns the generated files instead of writing them to `out_dir`.
+  pub fn generate_in_memory(self) -> Result<GeneratedBindings, Error> {
+    self.config()?.generate_in_memory()
+  }
+
+  /// Generates in memory and fails with `Error::Stale` if that differs from
+  /// what is in `out_dir`, without writing anything.
+  pub fn check(self) -> Result<(), Error> {
+    self.config()?.check()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use finch_frontend_api::FinchVariant;
+
+  fn primitive(name: &str, kind: TypeKind) -> FinchType {
+    FinchType {
+      display_name: name.to_string(),
+      kind,
+      canonical_type: None,
+      template_argument_types: None,
+    }
+  }
+
+  fn class(name: &str) -> FinchClass {
+    FinchClass {
+      name: name.to_string(),
+      c_name: format!("___finch_bindgen___demo___class___{}", name),
+      comments: None,
+      new: None,
+      drop: Some(FinchDrop {
+        class_name: name.to_string(),
+        fn_name: format!("___finch_bindgen___demo___class___{}___drop", name),
+      }),
+      clone: None,
+      statics: Vec::new(),
+      methods: Vec::new(),
+      getters: Vec::new(),
+      setters: Vec::new(),
+      operators: Vec::new(),
+    }
+  }
+
+  fn method(class_name: &str, name: &str, arg_types: Vec<FinchType>, ret_type: FinchType) -> FinchMethod {
+    FinchMethod {
+      class_name: class_name.to_string(),
+      method_name: name.to_string(),
+      fn_name: format!("___finch_bindgen___demo___class___{}___{}", class_name, name),
+      arg_names: (0..arg_types.len()).map(|i| format!("arg{}", i)).collect(),
+      arg_types,
+      ret_type,
+      consume: false,
+      mutable: false,
+      comments: None,
+    }
+  }
+
+  fn context(config: &Config) -> Context {
+    Context::new("demo".to_string(), config).unwrap()
+  }
+
+  /// The lines of `code` defining something outside of a class body.
+  fn definitions(code: &str) -> Vec<&str> {
+    code.lines().map(str::trim).filter(|x| x.ends_with('{') && x.contains("::") && !x.starts_with("namespace")).collect()
+  }
+
+  fn point() -> FinchClass {
+    let mut point = class("Point");
+    point.clone = Some(FinchClone {
+      class_name: "Point".to_string(),
+      fn_name: "___finch_bindgen___demo___class___Point___clone".to_string(),
+    });
+    point.methods.push(method("Point", "norm", Vec::new(), primitive("double", TypeKind::Double)));
+    point.operators = [FinchOperatorKind::PartialEq, FinchOperatorKind::PartialOrd, FinchOperatorKind::Display].iter().map(|kind| {
+      FinchOperator {
+        class_name: "Point".to_string(),
+        fn_name: format!("___finch_bindgen___demo___class___Point___{:?}", kind),
+        kind: *kind,
+      }
+    }).collect();
+    point
+  }
+
+  #[test]
+  fn methods_pass_arguments_after_self_and_return_the_result() {
+    let mut ctx = context(&Config::default());
+    let int = || primitive("int32_t", TypeKind::Int);
+
+    let mut scale = method("Point", "scale", vec![int(), int()], int());
+    let code = scale.to_impl(&mut ctx).unwrap();
+    assert!(code.contains("return ___finch_bindgen___demo___class___Point___scale(this->self, arg0, arg1);"), "{}", code);
+
+    scale.consume = true;
+    let code = scale.to_impl(&mut ctx).unwrap();
+    assert!(code.contains("auto value = ___finch_bindgen___demo___class___Point___scale(this->self, arg0, arg1);"), "{}", code);
+  }
+
+  #[test]
+  fn hashing_checks_the_pointer() {
+    let mut point = class("Point");
+    point.operators.push(FinchOperator {
+      class_name: "Point".to_string(),
+      fn_name: "___finch_bindgen___demo___class___Point___Hash".to_string(),
+      kind: FinchOperatorKind::Hash,
+    });
+
+    let code = point.to_std_hash(&context(&Config::default()));
+    let assert = code.find("value.self != nullptr").expect("no null check");
+    assert!(assert < code.find("___finch_bindgen___demo___class___Point___Hash(value.self)").unwrap(), "{}", code);
+  }
+
+  #[test]
+  fn header_definitions_are_inline() {
+    let code = point().to_impl(&mut context(&Config::default())).unwrap();
+    let definitions = definitions(&code);
+    assert_eq!(definitions.len(), 13);
+    for definition in definitions {
+      assert!(definition.starts_with("inline "), "{}", definition);
+    }
+  }
+
+  #[test]
+  fn separate_source_definitions_are_not_inline() {
+    let config = Config {
+      separate_source: true,
+      ..Config::default()
+    };
+    let code = point().to_impl(&mut context(&config)).unwrap();
+    let definitions = definitions(&code);
+    assert_eq!(definitions.len(), 13);
+    for definition in definitions {
+      assert!(!definition.contains("inline"), "{}", definition);
+    }
+  }
+
+  fn string() -> FinchType {
+    primitive("finch::bindgen::demo::FinchString", TypeKind::Record)
+  }
+
+  /// A bindgen container type such as `FinchVec<T>`.
+  fn record(name: &str, arguments: Vec<FinchType>) -> FinchType {
+    FinchType {
+      display_name: format!(
+        "finch::bindgen::demo::{}<{}>",
+        name,
+        arguments.iter().map(|x| x.display_name.as_str()).collect::<Vec<_>>().join(", "),
+      ),
+      kind: TypeKind::Record,
+      canonical_type: None,
+      template_argument_types: Some(arguments.into_iter().map(Some).collect()),
+    }
+  }
+
+  /// A pointer to a class, `const` for a shared borrow.
+  fn class_type(name: &str, shared: bool) -> FinchType {
+    primitive(
+      &format!("{}finch::bindgen::demo::___finch_bindgen___demo___class___{} *", if shared { "const " } else { "" }, name),
+      TypeKind::Pointer,
+    )
+  }
+
+  fn converted(result: Result<String, Unsupported>) -> String {
+    result.unwrap_or_else(|err| panic!("unsupported: {}", err.0))
+  }
+
+  fn unsupported(result: Result<String, Unsupported>) -> String {
+    match result {
+      Ok(code) => panic!("converted: {}", code),
+      Err(err) => err.0,
+    }
+  }
+
+  #[test]
+  fn vec_arguments_are_moved_into_a_rust_allocation() {
+    let mut ctx = context(&Config::default());
+    let int = primitive("int32_t", TypeKind::Int);
+
+    let code = converted(record("FinchVec", vec![int.clone()]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("static_cast<int32_t *>(finch::bindgen::demo::___finch_bindgen___demo___builtin___FinchVec___new(vec.size(), sizeof(int32_t), alignof(int32_t)))"), "{}", code);
+    assert!(code.contains("new (&finch.ptr[i]) int32_t{ value };"), "{}", code);
+    assert!(code.ends_with("}(arg0)"), "{}", code);
+
+    let code = converted(record("FinchVec", vec![string()]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("___finch_bindgen___demo___builtin___FinchString___new("), "{}", code);
+    assert!(!code.contains("___drop"), "{}", code);
+
+    let option = record("FinchOption", vec![int]);
+    let code = converted(record("FinchVec", vec![option.clone()]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("[](const nonstd::optional<int32_t> &opt)"), "{}", code);
+    assert!(code.contains("}(value)"), "{}", code);
+
+    let code = converted(record("FinchVec", vec![class_type("Point", false)]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("[](const std::vector<std::unique_ptr<Point>> &vec)"), "{}", code);
+    assert!(code.contains("value.self = nullptr;"), "{}", code);
+    assert!(code.contains("}(*value)"), "{}", code);
+
+    assert!(ctx.templates.contains("template struct FinchVec<int32_t>;"));
+    assert!(ctx.templates.contains(&format!("template struct FinchVec<{}>;", option.display_name)));
+  }
+
+  #[test]
+  fn vec_returns_convert_each_element_and_free_the_buffer() {
+    let mut ctx = context(&Config::default());
+    let drop = "finch::bindgen::demo::___finch_bindgen___demo___builtin___FinchVec___drop(finch.ptr, finch.cap";
+
+    let code = converted(record("FinchVec", vec![primitive("double", TypeKind::Double)]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("vec.push_back(finch.ptr[i]);"), "{}", code);
+    assert!(code.contains(&format!("{}, sizeof(double), alignof(double));", drop)), "{}", code);
+
+    let code = converted(record("FinchVec", vec![string()]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("___finch_bindgen___demo___builtin___FinchString___drop(finch);"), "{}", code);
+    assert!(code.contains("}(finch.ptr[i])"), "{}", code);
+    assert!(code.contains(drop), "{}", code);
+
+    let code = converted(record("FinchVec", vec![record("FinchOption", vec![string()])]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("-> std::vector<nonstd::optional<std::string>>"), "{}", code);
+    assert!(code.contains("___finch_bindgen___demo___builtin___FinchString___drop(finch);"), "{}", code);
+    assert!(code.contains(drop), "{}", code);
+
+    let code = converted(record("FinchVec", vec![class_type("Point", false)]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("vec.push_back(std::unique_ptr<Point>(new Point(finch.ptr[i])));"), "{}", code);
+    assert!(code.contains(drop), "{}", code);
+  }
+
+  #[test]
+  fn slice_arguments_are_borrowed() {
+    let mut ctx = context(&Config::default());
+
+    let code = converted(record("FinchSlice", vec![primitive("uint8_t", TypeKind::UChar)]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("[](finch::slice<uint8_t> slice)"), "{}", code);
+    assert!(code.contains("finch.ptr = slice.data();"), "{}", code);
+    assert!(ctx.use_slice);
+
+    let code = converted(record("FinchSlice", vec![primitive("uint8_t", TypeKind::UChar)]).convert_arg(&mut context(&Config {
+      cpp_standard: CppStandard::Cpp20,
+      ..Config::default()
+    }), "arg0".to_string()));
+    assert!(code.contains("[](std::span<const uint8_t> slice)"), "{}", code);
+
+    // Strings are converted for the call and freed again by the deleter.
+    let code = converted(record("FinchSlice", vec![string()]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("___finch_bindgen___demo___builtin___FinchString___new("), "{}", code);
+    assert!(code.contains("std::shared_ptr<std::vector<finch::bindgen::demo::FinchString>>"), "{}", code);
+    assert!(code.contains("finch::bindgen::demo::___finch_bindgen___demo___builtin___FinchString___drop(finch);"), "{}", code);
+    assert!(!code.contains("FinchVec"), "{}", code);
+
+    let code = converted(record("FinchSlice", vec![class_type("Point", true)]).convert_arg(&mut ctx, "arg0".to_string()));
+    assert!(code.contains("finch.push_back({ value->self });"), "{}", code);
+    assert!(!code.contains("shared_ptr"), "{}", code);
+
+    // Converting these would move them into Rust, which only borrows the slice.
+    let point = class_type("Point", false);
+    assert_eq!(unsupported(record("FinchSlice", vec![point.clone()]).convert_arg(&mut ctx, "arg0".to_string())), point.display_name);
+    let option = record("FinchOption", vec![string()]);
+    assert_eq!(unsupported(record("FinchSlice", vec![option.clone()]).convert_arg(&mut ctx, "arg0".to_string())), option.display_name);
+  }
+
+  #[test]
+  fn slice_returns_copy_the_borrowed_elements() {
+    let mut ctx = context(&Config::default());
+
+    let code = converted(record("FinchSlice", vec![primitive("uint8_t", TypeKind::UChar)]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("return finch::slice<uint8_t>(finch.ptr, finch.len);"), "{}", code);
+
+    let code = converted(record("FinchSlice", vec![string()]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("vec.push_back(std::string(finch.ptr[i].ptr, finch.ptr[i].len));"), "{}", code);
+    assert!(!code.contains("___drop"), "{}", code);
+
+    let code = converted(record("FinchSlice", vec![record("FinchOption", vec![string()])]).convert_ret(&mut ctx, "value".to_string()));
+    assert!(code.contains("return nonstd::optional<std::string>(std::string(finch.some._0.ptr, finch.some._0.len));"), "{}", code);
+    assert!(!code.contains("___drop"), "{}", code);
+
+    let point = class_type("Point", true);
+    assert_eq!(unsupported(record("FinchSlice", vec![point.clone()]).convert_ret(&mut ctx, "value".to_string())), point.display_name);
+  }
+
+  fn variant(name: &str, fields: Vec<(&str, FinchType)>) -> FinchVariant {
+    FinchVariant {
+      name: name.to_string(),
+      comments: None,
+      field_names: fields.iter().map(|x| x.0.to_string()).collect(),
+      field_types: fields.into_iter().map(|x| x.1).collect(),
+    }
+  }
+
+  /// `Inner` holds a string, `Outer` holds an `Inner` and an object.
+  fn enums(ctx: &mut Context) -> (FinchEnum, FinchEnum) {
+    let inner = FinchEnum {
+      name: "Inner".to_string(),
+      c_name: "___finch_bindgen___demo___enum___Inner".to_string(),
+      comments: None,
+      variants: vec![
+        variant("Number", vec![("x", primitive("int32_t", TypeKind::Int))]),
+        variant("Text", vec![("s", string())]),
+      ],
+    };
+    let outer = FinchEnum {
+      name: "Outer".to_string(),
+      c_name: "___finch_bindgen___demo___enum___Outer".to_string(),
+      comments: None,
+      variants: vec![
+        variant("Holds", vec![("inner", primitive(&inner.c_name, TypeKind::Record))]),
+        variant("Object", vec![("point", class_type("Point", false))]),
+        variant("Empty", Vec::new()),
+      ],
+    };
+    ctx.enums.insert(inner.c_name.clone(), inner.clone());
+    ctx.enums.insert(outer.c_name.clone(), outer.clone());
+    (inner, outer)
+  }
+
+  #[test]
+  fn enums_are_tagged_unions_before_cpp17() {
+    let mut ctx = context(&Config::default());
+    let (inner, outer) = enums(&mut ctx);
+
+    let code = inner.to_header(&mut ctx).unwrap();
+    assert!(code.contains("Tag tag;"), "{}", code);
+    assert!(code.contains("union {"), "{}", code);
+    assert!(code.contains("Number number_value;"), "{}", code);
+    assert!(code.contains("Inner(const Inner &other);"), "{}", code);
+    assert!(!code.contains("std::variant"), "{}", code);
+
+    // Objects are held by `std::unique_ptr`, which can't be copied.
+    let code = outer.to_header(&mut ctx).unwrap();
+    assert!(code.contains("union {"), "{}", code);
+    assert!(code.contains("Outer(Outer &&other);"), "{}", code);
+    assert!(!code.contains("Outer(const Outer &other);"), "{}", code);
+    assert!(!ctx.use_variant);
+
+    let code = inner.to_impl(&mut ctx).unwrap();
+    let definitions = definitions(&code);
+    assert!(!definitions.is_empty());
+    for definition in definitions {
+      assert!(definition.starts_with("inline "), "{}", definition);
+    }
+  }
+
+  #[test]
+  fn enums_are_variants_from_cpp17() {
+    let mut ctx = context(&Config {
+      cpp_standard: CppStandard::Cpp17,
+      ..Config::default()
+    });
+    let (inner, outer) = enums(&mut ctx);
+
+    let code = inner.to_header(&mut ctx).unwrap();
+    assert!(code.contains("std::variant<Number, Text> value;"), "{}", code);
+    assert!(!code.contains("union {"), "{}", code);
+
+    let code = outer.to_header(&mut ctx).unwrap();
+    assert!(code.contains("std::variant<Holds, Object, Empty> value;"), "{}", code);
+    assert!(ctx.use_variant);
+  }
+
+  #[test]
+  fn enums_come_after_the_enums_they_hold() {
+    let mut ctx = context(&Config::default());
+    let (inner, outer) = enums(&mut ctx);
+    let names = |enums: Vec<FinchEnum>| enums.into_iter().map(|x| x.name).collect::<Vec<_>>();
+
+    assert_eq!(names(sort_enums(&ctx, vec![outer.clone(), inner.clone()])), vec!["Inner", "Outer"]);
+    assert_eq!(names(sort_enums(&ctx, vec![inner, outer])), vec!["Inner", "Outer"]);
+
+    let maybe = variant("Maybe", vec![("inner", record("FinchOption", vec![primitive("___finch_bindgen___demo___enum___Inner", TypeKind::Record)]))]);
+    let wrapper = FinchEnum {
+      name: "Wrapper".to_string(),
+      c_name: "___finch_bindgen___demo___enum___Wrapper".to_string(),
+      comments: None,
+      variants: vec![maybe],
+    };
+    let inner = ctx.enums["___finch_bindgen___demo___enum___Inner"].clone();
+    assert_eq!(names(sort_enums(&ctx, vec![wrapper, inner])), vec!["Inner", "Wrapper"]);
+  }
+
+  #[test]
+  fn namespaces_must_be_identifiers() {
+    let namespace = |namespace: &str| Context::new("demo".to_string(), &Config {
+      namespace: Some(namespace.to_string()),
+      ..Config::default()
+    }).map(|x| x.namespace);
+
+    assert_eq!(namespace("::acme::geometry").ok().as_deref(), Some("acme::geometry"));
+    assert!(namespace("class").is_err());
+    assert!(namespace("acme::new").is_err());
+    assert!(namespace("acme::").is_err());
+    assert!(namespace("acme geometry").is_err());
+
+    let mut ctx = context(&Config::default());
+    let modules = |module: &str| vec![("Circle".to_string(), module.to_string())].into_iter().collect();
+    assert!(ctx.mirror_modules(modules("shapes::union")).is_err());
+    assert!(ctx.mirror_modules(modules("shapes::round")).is_ok());
+  }
+
+  #[test]
+  fn rust_tokens_skip_comments_and_literals() {
+    use RustToken::*;
+
+    let source = concat!(
+      "// mod a;\n",
+      "/* struct B; /* nested */ mod C; */\n",
+      "let s = \"mod d; \\\" struct E;\";\n",
+      "let r = r##\"struct F; \"# mod G;\"##;\n",
+      "let c = ('{', '\\'', b'}', '\\u{7d}');\n",
+      "fn f<'a>(x: &'a str) {}\n",
+      "mod r#type;\n",
+    );
+    let idents = rust_tokens(source).into_iter().filter_map(|x| match x {
+      Ident(x) => Some(x),
+      _ => None,
+    }).collect::<Vec<String>>();
+    assert_eq!(idents, vec!["let", "s", "let", "r", "let", "c", "fn", "f", "a", "x", "a", "str", "mod", "type"]);
+
+    let braces = rust_tokens(source).into_iter().filter(|x| *x == Punct('{') || *x == Punct('}')).count();
+    assert_eq!(braces, 2);
+    assert!(rust_tokens(source).contains(&Str("struct F; \"# mod G;".to_string())));
+  }
+
+  #[test]
+  fn module_paths_follow_mod_items() {
+    let dir = StagingDir::new().unwrap();
+    let write = |path: &str, contents: &str| {
+      let path = dir.0.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, contents).unwrap();
+    };
+
+    write("src/lib.rs", concat!(
+      "pub struct Root;\n",
+      "pub mod geometry;\n",
+      "mod shapes {\n",
+      "  pub enum Circle { Unit, Radius(f64) }\n",
+      "  pub(crate) mod round;\n",
+      "  fn local() { struct Local; }\n",
+      "}\n",
+      "#[path = \"other/renamed.rs\"]\n",
+      "mod renamed;\n",
+      "mod missing;\n",
+      "#[cfg(test)]\n",
+      "mod tests { struct Shared; }\n",
+    ));
+    write("src/geometry.rs", "mod points;\npub struct Line;\nstruct Shared;\n");
+    write("src/geometry/points/mod.rs", "/// A `struct Fake`.\npub struct Point { x: f64 }\n");
+    write("src/shapes/round.rs", "pub struct Ellipse;\n");
+    write("src/other/renamed.rs", "pub struct Renamed;\nmod nested;\n");
+    write("src/other/nested.rs", "pub struct Nested;\n");
+
+    let modules = module_paths(&dir.0.join("src/lib.rs")).unwrap();
+    let expected: BTreeMap<String, String> = vec![
+      ("Circle", "shapes"),
+      ("Ellipse", "shapes::round"),
+      ("Line", "geometry"),
+      ("Nested", "renamed::nested"),
+      ("Point", "geometry::points"),
+      ("Renamed", "renamed"),
+    ].into_iter().map(|(name, module)| (name.to_string(), module.to_string())).collect();
+    assert_eq!(modules, expected);
+  }
+
+  #[test]
+  fn module_items_are_declared_in_nested_namespaces() {
+    let mut ctx = context(&Config::default());
+    ctx.mirror_modules(vec![("Point".to_string(), "geometry::points".to_string())].into_iter().collect()).unwrap();
+
+    let mut point = class("Point");
+    point.operators.push(FinchOperator {
+      class_name: "Point".to_string(),
+      fn_name: "___finch_bindgen___demo___class___Point___Hash".to_string(),
+      kind: FinchOperatorKind::Hash,
+    });
+    let mut line = class("Line");
+    line.methods.push(method("Line", "start", Vec::new(), class_type("Point", false)));
+
+    let code = line.to_header(&mut ctx).unwrap();
+    assert!(code.contains("std::unique_ptr<::demo::geometry::points::Point> start() const;"), "{}", code);
+    assert_eq!(ctx.in_module("Line", code.clone()), code);
+
+    let code = point.to_header(&mut ctx).unwrap();
+    let code = ctx.in_module("Point", code);
+    assert!(code.starts_with("namespace geometry { namespace points {\n"), "{}", code);
+    assert!(code.ends_with("\n}}\n"), "{}", code);
+    assert!(point.to_std_hash(&ctx).contains("struct hash<demo::geometry::points::Point>"));
+
+    let mut ctx = context(&Config {
+      cpp_standard: CppStandard::Cpp17,
+      ..Config::default()
+    });
+    ctx.mirror_modules(vec![("Point".to_string(), "geometry::points".to_string())].into_iter().collect()).unwrap();
+    let code = point.to_header(&mut ctx).unwrap();
+    let code = ctx.in_module("Point", code);
+    assert!(code.starts_with("namespace geometry::points {\n"), "{}", code);
+  }
+
+  #[test]
+  fn indent_nests_by_brace_depth() {
+    let code = "namespace a {\n    struct B {\nint x;\n};\n}\n";
+    assert_eq!(indent(code), "namespace a {\n  struct B {\n    int x;\n  };\n}\n");
+  }
+
+  #[test]
+  fn indent_ignores_braces_in_strings_and_comments() {
+    let code = concat!(
+      "void f() {\n",
+      "auto s = \"{ \\\" {\";\n",
+      "auto c = '{';\n",
+      "// {\n",
+      "/* { */ int x;\n",
+      "/* {\n",
+      "* {\n",
+      "*/\n",
+      "}\n",
+      "int y;\n",
+    );
+    assert_eq!(indent(code), concat!(
+      "void f() {\n",
+      "  auto s = \"{ \\\" {\";\n",
+      "  auto c = '{';\n",
+      "  // {\n",
+      "  /* { */ int x;\n",
+      "  /* {\n",
+      "   * {\n",
+      "   */\n",
+      "}\n",
+      "int y;\n",
+    ));
+  }
+
+  #[test]
+  fn indent_treats_quotes_in_numbers_as_digit_separators() {
+    let code = "void f() {\nint x = 1'000'000;\nint y = 0x7f'ff;\n}\nauto c = u8'{';\nint z;\n";
+    assert_eq!(
+      indent(code),
+      "void f() {\n  int x = 1'000'000;\n  int y = 0x7f'ff;\n}\nauto c = u8'{';\nint z;\n",
+    );
+  }
+
+  #[test]
+  fn indent_outdents_access_labels() {
+    let code = "class A {\npublic:\nA();\n\nprivate:\nint x;\n};\n";
+    assert_eq!(indent(code), "class A {\npublic:\n  A();\n\nprivate:\n  int x;\n};\n");
+  }
+
+  #[test]
+  fn indent_leaves_preprocessor_lines_at_column_zero() {
+    let code = "namespace a {\n#ifdef X\nint x;\n#endif\n}\n";
+    assert_eq!(indent(code), "namespace a {\n#ifdef X\n  int x;\n#endif\n}\n");
+  }
+
+  #[test]
+  fn indent_drops_blank_lines_at_block_edges() {
+    let code = "struct A {\n\nint x;\n\nint y;\n\n};\n\nint z;\n";
+    assert_eq!(indent(code), "struct A {\n  int x;\n\n  int y;\n};\n\nint z;\n");
+  }
+
+  #[test]
+  fn inline_includes_inlines_each_file_once() {
+    let files: BTreeMap<String, String> = vec![
+      ("a.h".to_string(), "#pragma once\n#include \"b.h\"\nint a;\n".to_string()),
+      ("b.h".to_string(), "#pragma once\n#include <cstdint>\nint b;\n".to_string()),
+    ].into_iter().collect();
+    let code = "#pragma once\n#include \"a.h\"\n  #include \"b.h\"\n#include \"c.h\"\nint c;\n";
+
+    let mut included = HashSet::new();
+    assert_eq!(
+      inline_includes(code, &files, &mut included),
+      "#include <cstdint>\nint b;\nint a;\n#include \"c.h\"\nint c;\n",
+    );
+    assert_eq!(included.len(), 2);
+  }
+
+  #[test]
+  fn builder_options_override_the_config_file() {
+    let file: Options = toml::from_str(concat!(
+      "generate-cmake = true\n",
+      "unique-ptr = false\n",
+      "cpp-standard = 17\n",
+      "header-extension = \"hpp\"\n",
+    )).unwrap();
+    let builder = Builder::new().with_generate_cmake(false).with_namespace("a::b");
+
+    let config = builder.options.or(file).into_config();
+    assert!(!config.generate_cmake);
+    assert!(!config.unique_ptr);
+    assert_eq!(config.cpp_standard, CppStandard::Cpp17);
+    assert_eq!(config.namespace.as_deref(), Some("a::b"));
+    assert_eq!(config.header_extension, "hpp");
+    assert_eq!(config.include_dir, Config::default().include_dir);
+    assert_eq!(config.expected_results, Config::default().expected_results);
+  }
+
+  #[test]
+  fn config_file_rejects_unknown_options() {
+    assert!(toml::from_str::<Options>("generate-cmak = true\n").is_err());
+    assert!(toml::from_str::<Options>("cpp-standard = 15\n").is_err());
+  }
+
+  /// A configuration writing to a fresh scratch directory, removed when the
+  /// returned guard is dropped.
+  fn scratch_config() -> (StagingDir, Config) {
+    let dir = StagingDir::new().unwrap();
+    let config = Config {
+      out_dir: dir.0.clone(),
+      ..Config::default()
+    };
+    (dir, config)
+  }
+
+  fn stale_files_of(result: Result<(), Error>) -> Vec<PathBuf> {
+    match result {
+      Ok(()) => Vec::new(),
+      Err(Error::Stale { files, .. }) => files,
+      Err(err) => panic!("{}", err),
+    }
+  }
+
+  #[test]
+  fn check_only_compares_the_include_dir_by_default() {
+    let (_dir, config) = scratch_config();
+    let header = config.header_path("demo");
+    let outputs = vec![
+      (header.clone(), "// header\n".to_string()),
+      (config.out_dir.join("CMakeLists.txt"), "# cmake\n".to_string()),
+      (config.manifest_path(), MANIFEST_HEADER.to_string()),
+    ];
+
+    assert_eq!(stale_files_of(config.check_files(outputs.clone())), vec![header.clone()]);
+
+    std::fs::create_dir_all(header.parent().unwrap()).unwrap();
+    std::fs::write(&header, "// header\n").unwrap();
+    assert_eq!(stale_files_of(config.check_files(outputs.clone())), Vec::<PathBuf>::new());
+
+    let config = Config {
+      check_build_files: true,
+      ..config
+    };
+    assert_eq!(stale_files_of(config.check_files(outputs)), vec![config.out_dir.join("CMakeLists.txt")]);
+  }
+
+  #[test]
+  fn generate_removes_only_unedited_leftover_headers() {
+    let (dir, config) = scratch_config();
+    let config = Config {
+      out_dir: dir.0.join("out"),
+      ..config
+    };
+
+    let old = config.include_path("old.h");
+    let edited = config.include_path("edited.h");
+    let cmake = config.out_dir.join("CMakeLists.txt");
+    let outside = dir.0.join("outside.h");
+    config.write_files(config.with_manifest(vec![
+      (old.clone(), "// old\n".to_string()),
+      (edited.clone(), "// edited\n".to_string()),
+      (cmake.clone(), "# cmake\n".to_string()),
+    ])).unwrap();
+
+    std::fs::write(&edited, "// edited by hand\n").unwrap();
+    std::fs::write(&outside, "// outside\n").unwrap();
+    let mut manifest = std::fs::read_to_string(config.manifest_path()).unwrap();
+    manifest += &format!("{}  ../outside.h\n", content_hash(b"// outside\n"));
+    std::fs::write(config.manifest_path(), manifest).unwrap();
+
+    let header = config.header_path("demo");
+    let result = config.write_files(config.with_manifest(vec![(header.clone(), "// header\n".to_string())])).unwrap();
+    assert_eq!(result.removed, vec![old.clone()]);
+    assert!(!old.exists());
+    assert!(edited.exists());
+    assert!(cmake.exists());
+    assert!(outside.exists());
+    assert!(header.exists());
+  }
+
+  #[test]
+  fn check_reports_leftover_headers_as_deletions() {
+    let (_dir, config) = scratch_config();
+    let header = config.header_path("demo");
+    let old = config.include_path("old.h");
+    let outputs = config.with_manifest(vec![(header.clone(), "// header\n".to_string())]);
+    config.write_files(config.with_manifest(vec![(header.clone(), "// header\n".to_string()), (old.clone(), String::new())])).unwrap();
+
+    match config.check_files(outputs) {
+      Err(Error::Stale { files, diff }) => {
+        assert_eq!(files, vec![old]);
+        assert!(diff.is_empty());
+      },
+      result => panic!("{:?}", result),
+    }
+  }
+
+  #[test]
+  fn results_can_be_sent_between_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Result<Manifest, Error>>();
+    assert_send_sync::<Result<GeneratedBindings, Error>>();
+  }
+
+  #[test]
+  fn cpp_standard_from_str() {
+    assert_eq!("17".parse(), Ok(CppStandard::Cpp17));
+    assert_eq!("c++11".parse(), Ok(CppStandard::Cpp11));
+    assert_eq!("C++23".parse(), Ok(CppStandard::Cpp23));
+    assert!("c++15".parse::<CppStandard>().is_err());
+    assert!("c++c++17".parse::<CppStandard>().is_err());
+    assert!("gnu++17".parse::<CppStandard>().is_err());
+    assert!("".parse::<CppStandard>().is_err());
+  }
+
+  #[test]
+  fn cpp_standards_are_ordered_by_year() {
+    assert!(CppStandard::Cpp11 < CppStandard::Cpp14);
+    assert!(CppStandard::Cpp20 < CppStandard::Cpp23);
+    assert_eq!(CppStandard::Cpp20.number(), 20);
   }
 }
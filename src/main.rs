@@ -1,7 +1,274 @@
-use std::error::Error;
+use std::path::PathBuf;
+use clap::Parser;
 use finch_cc::*;
 
-fn main() -> Result<(), Box<dyn Error>> {
-  // generate(true)
-  Ok(())
+/// Generates C++ bindings for the Rust crate in the current directory.
+#[derive(Parser)]
+#[command(name = "finch-cc", version)]
+struct Args {
+  /// Directory to write CMakeLists.txt and the include directory to [default: the crate directory]
+  #[arg(long, value_name = "DIR")]
+  out_dir: Option<PathBuf>,
+
+  /// Cargo.toml of the crate to generate bindings for
+  #[arg(long, value_name = "PATH")]
+  manifest_path: Option<PathBuf>,
+
+  /// Generate a CMakeLists.txt (the default)
+  #[arg(long, overrides_with = "no_cmake")]
+  cmake: bool,
+
+  /// Don't generate a CMakeLists.txt
+  #[arg(long, overrides_with = "cmake")]
+  no_cmake: bool,
+
+  /// Return expected<T, E> from methods returning a Result instead of throwing
+  #[arg(long, overrides_with = "no_expected_results")]
+  expected_results: bool,
+
+  /// Throw the errors of methods returning a Result (the default)
+  #[arg(long, overrides_with = "expected_results")]
+  no_expected_results: bool,
+
+  /// Return owned objects as std::unique_ptr (the default)
+  #[arg(long, overrides_with = "raw_pointers")]
+  unique_ptr: bool,
+
+  /// Return owned objects as raw pointers instead of std::unique_ptr
+  #[arg(long, overrides_with = "unique_ptr")]
+  raw_pointers: bool,
+
+  /// Leave out items using unsupported types instead of failing
+  #[arg(long, overrides_with = "no_skip_unsupported")]
+  skip_unsupported: bool,
+
+  /// Fail on items using unsupported types (the default)
+  #[arg(long, overrides_with = "skip_unsupported")]
+  no_skip_unsupported: bool,
+
+  /// How to format the generated code
+  #[arg(long, value_enum, value_name = "FORMATTER")]
+  formatter: Option<FormatterArg>,
+
+  /// Style passed to clang-format, implies --formatter clang-format [default: Google]
+  #[arg(long, value_name = "STYLE", conflicts_with = "clang_format_file")]
+  clang_format_style: Option<String>,
+
+  /// .clang-format file to take the style from, implies --formatter clang-format
+  #[arg(long, value_name = "PATH")]
+  clang_format_file: Option<PathBuf>,
+
+  /// C++ standard the generated code is compiled with: 11, 14, 17, 20 or 23 [default: 11]
+  #[arg(long, value_name = "STANDARD")]
+  cpp_standard: Option<CppStandard>,
+
+  /// Write the definitions to a {crate}.cpp built by CMakeLists.txt instead of a header
+  #[arg(long, overrides_with = "no_separate_source")]
+  separate_source: bool,
+
+  /// Write the definitions to a header included by {crate}.h (the default)
+  #[arg(long, overrides_with = "separate_source")]
+  no_separate_source: bool,
+
+  /// Merge all generated and vendored headers into a single {crate}.h
+  #[arg(long, overrides_with = "no_single_header")]
+  single_header: bool,
+
+  /// Write each header to its own file (the default)
+  #[arg(long, overrides_with = "single_header")]
+  no_single_header: bool,
+
+  /// Namespace to declare the classes in, e.g. acme::geometry [default: the crate name]
+  #[arg(long, value_name = "NAMESPACE")]
+  namespace: Option<String>,
+
+  /// Declare each class in a namespace nested after its Rust module, e.g. acme::geometry::shapes
+  #[arg(long, overrides_with = "no_module_namespaces")]
+  module_namespaces: bool,
+
+  /// Declare every class directly in the namespace (the default)
+  #[arg(long, overrides_with = "module_namespaces")]
+  no_module_namespaces: bool,
+
+  /// Directory under the output directory added to the include path [default: include]
+  #[arg(long, value_name = "DIR")]
+  include_dir: Option<PathBuf>,
+
+  /// Directory under the include directory to put the headers in, e.g. acme/geometry
+  #[arg(long, value_name = "DIR")]
+  include_prefix: Option<PathBuf>,
+
+  /// Extension of the generated and vendored headers [default: h]
+  #[arg(long, value_name = "EXT")]
+  header_extension: Option<String>,
+
+  /// Name of the main header, {crate} and {ext} are replaced [default: {crate}.{ext}]
+  #[arg(long, value_name = "TEMPLATE")]
+  header_name: Option<String>,
+
+  /// Name of the header with the definitions [default: {crate}-impl.{ext}]
+  #[arg(long, value_name = "TEMPLATE")]
+  impl_name: Option<String>,
+
+  /// Name of the raw FFI header [default: {crate}-finch_bindgen.{ext}]
+  #[arg(long, value_name = "TEMPLATE")]
+  bindgen_name: Option<String>,
+
+  /// Path of the separate source file under the output directory [default: {crate}.cpp]
+  #[arg(long, value_name = "TEMPLATE")]
+  source_name: Option<String>,
+
+  /// Check that the files in the output directory are up to date instead of writing them
+  #[arg(long)]
+  check: bool,
+
+  /// With --check, also compare CMakeLists.txt and the separate source file
+  #[arg(long, requires = "check", overrides_with = "no_check_build_files")]
+  check_build_files: bool,
+
+  /// With --check, only compare the files in the include directory (the default)
+  #[arg(long, requires = "check", overrides_with = "check_build_files")]
+  no_check_build_files: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum FormatterArg {
+  None,
+  Indent,
+  ClangFormat,
+}
+
+impl Args {
+  fn formatter(&self) -> Option<Formatter> {
+    if let Some(path) = &self.clang_format_file {
+      return Some(Formatter::ClangFormatFile(path.clone()));
+    }
+
+    let style = self.clang_format_style.clone();
+    match self.formatter {
+      Some(FormatterArg::None) => Some(Formatter::None),
+      Some(FormatterArg::Indent) => Some(Formatter::Indent),
+      Some(FormatterArg::ClangFormat) => Some(Formatter::ClangFormat(style.unwrap_or_else(|| "Google".to_string()))),
+      None => style.map(Formatter::ClangFormat),
+    }
+  }
+}
+
+/// The value of a pair of `--x`/`--no-x` flags, if either was given. They
+/// override each other, so at most one is set.
+fn flag(yes: bool, no: bool) -> Option<bool> {
+  if yes {
+    Some(true)
+  } else if no {
+    Some(false)
+  } else {
+    None
+  }
+}
+
+fn run(mut args: Args) -> Result<(), Error> {
+  // Paths on the command line are relative to where the tool was started, not
+  // to the crate. The include layout and file names are relative to the output
+  // directory instead.
+  let cwd = std::env::current_dir()?;
+  args.out_dir = args.out_dir.map(|x| cwd.join(x));
+  args.clang_format_file = args.clang_format_file.map(|x| cwd.join(x));
+
+  // The crate is found the same way a build script finds it, from the working
+  // directory.
+  if let Some(manifest_path) = &args.manifest_path {
+    let manifest_path = cwd.join(manifest_path);
+    std::env::set_current_dir(manifest_path.parent().unwrap_or(&cwd))?;
+  }
+
+  let mut builder = Builder::new();
+
+  if let Some(out_dir) = args.out_dir.clone() {
+    builder = builder.with_out_dir(out_dir);
+  }
+
+  if let Some(value) = flag(args.cmake, args.no_cmake) {
+    builder = builder.with_generate_cmake(value);
+  }
+
+  if let Some(value) = flag(args.expected_results, args.no_expected_results) {
+    builder = builder.with_expected_results(value);
+  }
+
+  if let Some(value) = flag(args.unique_ptr, args.raw_pointers) {
+    builder = builder.with_unique_ptr(value);
+  }
+
+  if let Some(value) = flag(args.skip_unsupported, args.no_skip_unsupported) {
+    builder = builder.with_skip_unsupported(value);
+  }
+
+  if let Some(formatter) = args.formatter() {
+    builder = builder.with_formatter(formatter);
+  }
+
+  if let Some(standard) = args.cpp_standard {
+    builder = builder.with_cpp_standard(standard);
+  }
+
+  if let Some(value) = flag(args.separate_source, args.no_separate_source) {
+    builder = builder.with_separate_source(value);
+  }
+
+  if let Some(value) = flag(args.single_header, args.no_single_header) {
+    builder = builder.with_single_header(value);
+  }
+
+  if let Some(namespace) = args.namespace {
+    builder = builder.with_namespace(namespace);
+  }
+
+  if let Some(value) = flag(args.module_namespaces, args.no_module_namespaces) {
+    builder = builder.with_module_namespaces(value);
+  }
+
+  if let Some(include_dir) = args.include_dir {
+    builder = builder.with_include_dir(include_dir);
+  }
+
+  if let Some(include_prefix) = args.include_prefix {
+    builder = builder.with_include_prefix(include_prefix);
+  }
+
+  if let Some(extension) = args.header_extension {
+    builder = builder.with_header_extension(extension);
+  }
+
+  if let Some(template) = args.header_name {
+    builder = builder.with_header_name(template);
+  }
+
+  if let Some(template) = args.impl_name {
+    builder = builder.with_impl_name(template);
+  }
+
+  if let Some(template) = args.bindgen_name {
+    builder = builder.with_bindgen_name(template);
+  }
+
+  if let Some(template) = args.source_name {
+    builder = builder.with_source_name(template);
+  }
+
+  if let Some(value) = flag(args.check_build_files, args.no_check_build_files) {
+    builder = builder.with_check_build_files(value);
+  }
+
+  if args.check {
+    builder.check()
+  } else {
+    builder.generate().map(|_| ())
+  }
+}
+
+fn main() {
+  if let Err(err) = run(Args::parse()) {
+    eprintln!("error: {}", err);
+    std::process::exit(1);
+  }
 }